@@ -0,0 +1,64 @@
+use std::fmt;
+
+use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize};
+
+// number of base units in a whole coin
+pub const COIN: u64 = 1_000_000;
+// no amount, and no sum of amounts, may ever exceed the total money supply
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_base_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    pub const fn from_coins(coins: u64) -> Amount {
+        Amount(coins * COIN)
+    }
+
+    pub fn to_base_units(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_valid_money(self) -> bool {
+        self <= MAX_MONEY
+    }
+
+    // arithmetic fails instead of wrapping, and anything above MAX_MONEY counts as an overflow
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount).filter(|sum| sum.is_valid_money())
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Amount> {
+        self.0.checked_mul(factor).map(Amount).filter(|product| product.is_valid_money())
+    }
+
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, |sum, amount| sum.checked_add(amount))
+    }
+
+    pub fn fmt_base_units(&self) -> String {
+        format!("{} units", self.0.to_formatted_string(&Locale::en))
+    }
+
+    pub fn fmt_coins(&self) -> String {
+        format!("{}.{:06} coins", (self.0 / COIN).to_formatted_string(&Locale::en), self.0 % COIN)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.fmt_coins())
+    }
+}
@@ -1,32 +1,47 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
 use serde::{Serialize, Deserialize};
 use num_format::{Locale, ToFormattedString};
-use crate::transactions::Tx;
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::hashes::{BlockHash, MerkleRoot, Txid};
+use crate::input::OutPoint;
+use crate::mempool::Mempool;
+use crate::output::Output;
+use crate::params::ChainParams;
+use crate::pow::{self, PowJob};
+use crate::transactions::{Tx, TxError};
 
 pub const MAX_BLOCK_SIZE: u32 = 100000;
-pub const BLOCK_REWARD: u32 = 5000000;
+pub const HEADER_BYTES: u32 = 156;
+// how far ahead of the local clock a block's timestamp may be
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Block {
     pub index: u32,
-    pub hash: [u8;32],
-    pub previous_hash: [u8;32],
+    pub hash: BlockHash,
+    pub previous_hash: BlockHash,
     pub time: u64,
     pub target: u64,
     pub nonce: u64,
+    // merkle root over txids, and a separate one over witness ids so signatures are still committed to
+    pub merkle_root: MerkleRoot,
+    pub witness_root: MerkleRoot,
     pub transactions: Vec<Tx>,
 }
 
 impl Block {
     pub fn print(&self) {
         println!("\n-------------------------------------------------------------------------------");
-        print!("Block: {} ",self.index);
-        self.hash.iter().for_each(|hex|print!("{:02x}",hex));
-        println!("\nHeader Data: ");
-        print!("\nPrevious block: ");
-        self.previous_hash.iter().for_each(|hex|print!("{:02x}",hex));
-        println!("\nUnix Timestamp: {}",self.time);
+        println!("Block: {} {}",self.index, self.hash);
+        println!("Header Data: ");
+        println!("\nPrevious block: {}",self.previous_hash);
+        println!("Unix Timestamp: {}",self.time);
         println!("Target {:016x}", self.target);
         println!("Nonce: {:016x}", self.nonce);
+        println!("Merkle root: {}", self.merkle_root);
+        println!("Witness root: {}", self.witness_root);
         println!("\nTransactions: ");
         self.transactions.iter().for_each(|transaction| transaction.print());
         println!("\n\nTotal block size: {} Bytes",self.get_size().to_formatted_string(&Locale::en));
@@ -35,11 +50,116 @@ impl Block {
     }
 
     pub fn get_size(&self) -> u32{
-        const HEADER_BYTES: u32 = 92;
         let tx_bytes: u32 = self.transactions.iter().map(|tx|tx.get_size()).sum();
         HEADER_BYTES + tx_bytes
     }
 
+    pub fn calc_merkle_root(transactions: &Vec<Tx>) -> MerkleRoot {
+        Self::merkle_root_of(transactions.iter().map(|tx| tx.txid.to_bytes()).collect())
+    }
+
+    pub fn calc_witness_root(transactions: &Vec<Tx>) -> MerkleRoot {
+        Self::merkle_root_of(transactions.iter().map(|tx| tx.wtxid().to_bytes()).collect())
+    }
+
+    // pairs of hashes are combined level by level, an odd hash out is paired with itself
+    fn merkle_root_of(mut level: Vec<[u8;32]>) -> MerkleRoot {
+        if level.is_empty() {
+            return MerkleRoot::ZERO;
+        }
+        while level.len() > 1 {
+            level = level.chunks(2).map(|pair| {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(&pair[0]);
+                hasher.update(pair.get(1).unwrap_or(&pair[0]));
+                *hasher.finalize().as_bytes()
+            }).collect();
+        }
+        MerkleRoot::from_bytes(level[0])
+    }
+
+    pub fn pow_job(&self) -> PowJob {
+        PowJob { index: self.index, previous_hash: self.previous_hash, merkle_root: self.merkle_root, witness_root: self.witness_root, time: self.time, target: self.target }
+    }
+
+    // full check of a block meant to extend the tip of chain, now is the local clock used to bound the timestamp
+    pub fn validate(&self, chain: &Blockchain, params: &ChainParams, now: u64) -> Result<(), BlockError> {
+        let tip = chain.chain.last().unwrap();
+        if self.previous_hash != tip.hash {
+            return Err(BlockError::NotOnTip { tip: tip.hash });
+        }
+        self.check_header(tip, now)?;
+        if self.get_size() > MAX_BLOCK_SIZE {
+            return Err(BlockError::TooLarge(self.get_size()));
+        }
+        if let Some(tx) = self.transactions.iter().find(|tx| tx.txid != Tx::generate_txid(&tx.inputs, &tx.outputs)) {
+            return Err(BlockError::BadTxid(tx.txid));
+        }
+        if self.merkle_root != Self::calc_merkle_root(&self.transactions) {
+            return Err(BlockError::BadMerkleRoot);
+        }
+        if self.witness_root != Self::calc_witness_root(&self.transactions) {
+            return Err(BlockError::BadWitnessRoot);
+        }
+        self.check_coinbase(chain, params)
+    }
+
+    // the checks that only need the parent, enough to keep a block on another branch until that branch is
+    // connected and its transactions can be checked too
+    pub fn check_header(&self, parent: &Block, now: u64) -> Result<(), BlockError> {
+        if self.index != parent.index + 1 {
+            return Err(BlockError::BadIndex { expected: parent.index + 1 });
+        }
+        // there's no retargeting, every block keeps its parent's target
+        if self.target != parent.target {
+            return Err(BlockError::BadTarget { expected: parent.target });
+        }
+        if self.time < parent.time || self.time > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::BadTime);
+        }
+        if self.hash != self.pow_job().hash(self.nonce) {
+            return Err(BlockError::BadHash);
+        }
+        if !pow::meets_target(&self.hash, self.target) {
+            return Err(BlockError::HighHash);
+        }
+        Ok(())
+    }
+
+    // the coinbase must be the first transaction and may claim at most the subsidy plus the fees of the block.
+    // transactions may spend the utxo set or outputs created earlier in the same block, each outpoint only once
+    pub fn check_coinbase(&self, chain: &Blockchain, params: &ChainParams) -> Result<(), BlockError> {
+        let coinbase = match self.transactions.first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err(BlockError::MissingCoinbase),
+        };
+        if self.transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+            return Err(BlockError::MultipleCoinbase);
+        }
+        let mut created: HashMap<OutPoint, Output> = HashMap::new();
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+        coinbase.outputs.iter().enumerate().for_each(|(vout, output)| { created.insert(OutPoint { txid: coinbase.txid, vout: vout as u32 }, output.clone()); });
+
+        let mut allowed = params.block_subsidy(self.index);
+        for tx in self.transactions.iter().skip(1) {
+            // checked before the fee, which would otherwise count an outpoint spent twice by the same transaction twice
+            let duplicate = tx.find_duplicate_input().or(tx.inputs.iter().map(|input| input.outpoint()).find(|outpoint| spent.contains(outpoint)));
+            if let Some(outpoint) = duplicate {
+                return Err(BlockError::InvalidTx(TxError::DuplicateInput(outpoint)));
+            }
+            let fee = tx.calc_mining_fee_from(|outpoint| created.get(outpoint).or(chain.utxos.get(outpoint)).cloned())
+                .map_err(BlockError::InvalidTx)?;
+            allowed = allowed.checked_add(fee).ok_or(BlockError::AmountOverflow)?;
+            tx.inputs.iter().for_each(|input| { spent.insert(input.outpoint()); });
+            tx.outputs.iter().enumerate().for_each(|(vout, output)| { created.insert(OutPoint { txid: tx.txid, vout: vout as u32 }, output.clone()); });
+        }
+        let claimed = Amount::checked_sum(coinbase.outputs.iter().map(|out| out.amount)).ok_or(BlockError::AmountOverflow)?;
+        if claimed > allowed {
+            return Err(BlockError::CoinbaseTooLarge { claimed, allowed });
+        }
+        Ok(())
+    }
+
     pub fn to_json(&self) -> Result<String, Box<dyn Error>> {
         Ok(serde_json::to_string(self)?)
     }
@@ -48,4 +168,83 @@ impl Block {
     pub fn from_json(json_str: &str) -> Result<Self, Box<dyn Error>> {
         Ok(serde_json::from_str(json_str)?)
     }
-}
\ No newline at end of file
+}
+
+// everything needed to build and solve a block on top of tip, without access to the node
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockTemplate {
+    pub index: u32,
+    pub previous_hash: BlockHash,
+    pub time: u64,
+    pub target: u64,
+    // the most the coinbase may pay out, subsidy plus fees
+    pub coinbase_value: Amount,
+    pub fees: Amount,
+    // space reserved for the coinbase, a bigger one pushes the block over MAX_BLOCK_SIZE
+    pub coinbase_size: u32,
+    // to be included after the coinbase, in this order
+    pub transactions: Vec<Tx>,
+}
+
+impl BlockTemplate {
+    // fails if the subsidy and fees together are more than MAX_MONEY, which only a broken mempool could produce
+    pub fn new(tip: &Block, pool: &Mempool, params: &ChainParams, coinbase_size: u32, now: u64) -> Result<BlockTemplate, BlockError> {
+        let index = tip.index + 1;
+        let (transactions, fees) = pool.calc_valid_tx_pool_and_fees((MAX_BLOCK_SIZE - HEADER_BYTES).saturating_sub(coinbase_size));
+        let coinbase_value = params.block_subsidy(index).checked_add(fees).ok_or(BlockError::AmountOverflow)?;
+        Ok(BlockTemplate { index, previous_hash: tip.hash, time: now.max(tip.time), target: tip.target, coinbase_value, fees, coinbase_size, transactions })
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockError{
+    // doesn't build on the current tip, either stale or on another branch
+    NotOnTip { tip: BlockHash },
+    // the parent is neither in the chain nor on a known side branch. holds the first missing ancestor, which is the
+    // parent unless that is waiting as an orphan too
+    UnknownParent(BlockHash),
+    AlreadyKnown,
+    BadIndex { expected: u32 },
+    BadTarget { expected: u64 },
+    // before the parent's timestamp, or too far in the future
+    BadTime,
+    TooLarge(u32),
+    BadTxid(Txid),
+    BadMerkleRoot,
+    BadWitnessRoot,
+    // the hash isn't the hash of the header
+    BadHash,
+    // the hash doesn't meet the target
+    HighHash,
+    MissingCoinbase,
+    MultipleCoinbase,
+    CoinbaseTooLarge { claimed: Amount, allowed: Amount },
+    InvalidTx(TxError),
+    AmountOverflow,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::NotOnTip { tip } => write!(f, "does not build on the current tip {tip}"),
+            BlockError::UnknownParent(missing) => write!(f, "ancestor {missing} is unknown"),
+            BlockError::AlreadyKnown => write!(f, "already in the chain"),
+            BlockError::BadIndex { expected } => write!(f, "index should be {expected}"),
+            BlockError::BadTarget { expected } => write!(f, "target should be {expected:016x}"),
+            BlockError::BadTime => write!(f, "timestamp out of range"),
+            BlockError::TooLarge(size) => write!(f, "size {size} is over the {MAX_BLOCK_SIZE} byte limit"),
+            BlockError::BadTxid(txid) => write!(f, "transaction {txid} has the wrong txid"),
+            BlockError::BadMerkleRoot => write!(f, "merkle root mismatch"),
+            BlockError::BadWitnessRoot => write!(f, "witness root mismatch"),
+            BlockError::BadHash => write!(f, "hash does not match the header"),
+            BlockError::HighHash => write!(f, "hash does not meet the target"),
+            BlockError::MissingCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockError::MultipleCoinbase => write!(f, "more than one coinbase"),
+            BlockError::CoinbaseTooLarge { claimed, allowed } => write!(f, "coinbase claims {} but only {} is allowed", claimed.fmt_base_units(), allowed.fmt_base_units()),
+            BlockError::InvalidTx(e) => write!(f, "invalid transaction: {e}"),
+            BlockError::AmountOverflow => write!(f, "amount overflow"),
+        }
+    }
+}
+
+impl Error for BlockError {}
@@ -1,7 +1,56 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
 use crate::block::Block;
+use crate::hashes::BlockHash;
+use crate::input::OutPoint;
+use crate::output::Output;
+use crate::params::ChainParams;
+use crate::storage;
+
+// file in the data directory the chain is saved to
+pub const CHAIN_FILE: &str = "chain.json";
+
+// side blocks this far below the tip are forgotten, a branch that far behind won't catch up
+pub const MAX_SIDE_BRANCH_DEPTH: u32 = 100;
+
+// blocks kept while their parent is unknown, enough to catch up on a stretch of missed blocks
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockchainInfo {
+    pub network: String,
+    pub height: u32,
+    pub tip: BlockHash,
+    // every coin created so far, all by coinbase subsidies
+    pub issued_supply: Amount,
+}
+
+impl BlockchainInfo {
+    pub fn print(&self) {
+        println!("Network: {}", self.network);
+        println!("Height: {}", self.height);
+        println!("Tip: {}", self.tip);
+        println!("Issued supply: {}", self.issued_supply);
+    }
+}
 
 pub struct Blockchain {
-    pub chain: Vec<Block>
+    pub chain: Vec<Block>,
+    // outputs of the current chain that haven't been spent yet
+    pub utxos: HashMap<OutPoint, Output>,
+    // outputs each connected block spent, so disconnecting it can put them back
+    undo: HashMap<BlockHash, Vec<(OutPoint, Output)>>,
+    // blocks on other branches, including ones disconnected from this chain, kept in case their branch overtakes it
+    side_blocks: HashMap<BlockHash, Block>,
+    // blocks whose parent hasn't arrived yet, connected or kept as side blocks once it does
+    orphan_blocks: HashMap<BlockHash, Block>,
 }
 
 impl Blockchain {
@@ -13,15 +62,138 @@ impl Blockchain {
         self.chain.last().unwrap().index
     }
 
-    pub fn get_current_hash(&self) -> [u8;32] { self.chain.last().unwrap().hash }
+    pub fn get_current_hash(&self) -> BlockHash { self.chain.last().unwrap().hash }
+
+    pub fn get_issued_supply(&self, params: &ChainParams) -> Amount { params.issued_supply(self.get_height()) }
 
+    pub fn get_info(&self, params: &ChainParams) -> BlockchainInfo {
+        BlockchainInfo { network: params.network.name().to_string(), height: self.get_height(), tip: self.get_current_hash(), issued_supply: self.get_issued_supply(params) }
+    }
+
+    // connects the block on top of the chain, spending its inputs and adding its outputs to the utxo set
     pub fn add_block(&mut self, candidate_block: Block) {
-            self.chain.push(candidate_block);
+        let mut spent = vec![];
+        for tx in candidate_block.transactions.iter() {
+            for input in tx.inputs.iter().filter(|input| !input.is_coinbase()) {
+                if let Some(output) = self.utxos.remove(&input.outpoint()) {
+                    spent.push((input.outpoint(), output));
+                }
+            }
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                self.utxos.insert(OutPoint { txid: tx.txid, vout: vout as u32 }, output.clone());
+            }
+        }
+        self.undo.insert(candidate_block.hash, spent);
+        self.side_blocks.remove(&candidate_block.hash);
+        self.chain.push(candidate_block);
+    }
+
+    // removes the tip, restoring the outputs it spent and dropping the ones it created. the block is kept as a side
+    // block, so its branch can be switched back to. the genesis block stays
+    pub fn disconnect_tip(&mut self) -> Option<Block> {
+        if self.chain.len() <= 1 {
+            return None;
+        }
+        let block = self.chain.pop()?;
+        for tx in block.transactions.iter() {
+            for vout in 0..tx.outputs.len() as u32 {
+                self.utxos.remove(&OutPoint { txid: tx.txid, vout });
+            }
+        }
+        for (outpoint, output) in self.undo.remove(&block.hash).unwrap_or_default() {
+            self.utxos.insert(outpoint, output);
+        }
+        self.add_side_block(block.clone());
+        Some(block)
+    }
+
+    pub fn get_block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.chain.iter().rev().find(|block| block.hash == *hash)
+    }
+
+    pub fn get_side_block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.side_blocks.get(hash)
+    }
+
+    pub fn add_side_block(&mut self, block: Block) {
+        let height = self.get_height();
+        self.side_blocks.retain(|_, side| side.index + MAX_SIDE_BRANCH_DEPTH > height);
+        self.side_blocks.insert(block.hash, block);
+    }
+
+    pub fn remove_side_block(&mut self, hash: &BlockHash) -> Option<Block> {
+        self.side_blocks.remove(hash)
+    }
+
+    // keeps a block whose parent is unknown. once there are too many, the one furthest above the chain goes, since
+    // the orphans closest to the chain are the ones that connect first
+    pub fn add_orphan_block(&mut self, block: Block) {
+        self.orphan_blocks.insert(block.hash, block);
+        if self.orphan_blocks.len() > MAX_ORPHAN_BLOCKS {
+            let highest = self.orphan_blocks.values().max_by_key(|orphan| orphan.index).map(|orphan| orphan.hash).unwrap();
+            self.orphan_blocks.remove(&highest);
+        }
+    }
+
+    // removes and returns the orphans building on parent
+    pub fn take_orphan_children(&mut self, parent: &BlockHash) -> Vec<Block> {
+        let children: Vec<BlockHash> = self.orphan_blocks.values().filter(|orphan| orphan.previous_hash == *parent).map(|orphan| orphan.hash).collect();
+        children.iter().filter_map(|hash| self.orphan_blocks.remove(hash)).collect()
+    }
+
+    // follows the orphans back from hash to the first block that isn't held, the one to ask peers for
+    pub fn get_missing_ancestor(&self, hash: &BlockHash) -> BlockHash {
+        let mut hash = *hash;
+        while let Some(orphan) = self.orphan_blocks.get(&hash) {
+            hash = orphan.previous_hash;
+        }
+        hash
+    }
+
+    // the side blocks from where their branch leaves the chain up to hash, oldest first. None if the branch
+    // doesn't lead back to the chain
+    pub fn get_branch(&self, hash: &BlockHash) -> Option<Vec<Block>> {
+        let mut branch = vec![];
+        let mut hash = *hash;
+        while self.get_block(&hash).is_none() {
+            let block = self.side_blocks.get(&hash)?;
+            hash = block.previous_hash;
+            branch.push(block.clone());
+        }
+        branch.reverse();
+        Some(branch)
+    }
+
+    // saves every block after genesis, side branches aren't kept
+    pub fn save(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(data_dir)?;
+        storage::write_atomic(&data_dir.join(CHAIN_FILE), &serde_json::to_string(&self.chain[1..])?)?;
+        Ok(())
+    }
+
+    // rebuilds a saved chain on top of genesis, validating every block again as it's connected. a block that
+    // fails ends the chain there
+    pub fn load(params: &ChainParams, data_dir: &Path) -> Result<Blockchain, Box<dyn Error>> {
+        let mut chain = Self::create_from_genesis(params.genesis_block());
+        let path = data_dir.join(CHAIN_FILE);
+        if !path.exists() {
+            return Ok(chain);
+        }
+        let blocks: Vec<Block> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for block in blocks {
+            if let Err(e) = block.validate(&chain, params, now) {
+                eprintln!("Saved block {} {} is invalid, loading stopped: {e}", block.index, block.hash);
+                break;
+            }
+            chain.add_block(block);
+        }
+        Ok(chain)
     }
 
     pub fn create_from_genesis(genesis: Block) -> Blockchain {
         let mut chain = vec![];
         chain.push(genesis);
-        Blockchain { chain }
+        Blockchain { chain, utxos: HashMap::new(), undo: HashMap::new(), side_blocks: HashMap::new(), orphan_blocks: HashMap::new() }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,203 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::blockchain::BlockchainInfo;
+use crate::hashes::{Address, BlockHash};
+use crate::mempool::MempoolStats;
+use crate::miner::Miner;
+use crate::node::Node;
+use crate::params::{ChainParams, Network};
+use crate::payout::Payout;
+use crate::pow;
+use crate::rpc::{self, RpcRequest};
+use crate::stratum::{self, StratumServer};
+
+pub const DATA_DIR: &str = ".chain";
+// how long each thread count is benchmarked for
+pub const BENCHMARK_SECS: u64 = 5;
+
+pub async fn run(args: Vec<String>) {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("node") => {
+            let params = get_params(&args);
+            let mut node = Node::new(&params, data_dir(&params));
+            node.send_recv_consensus().await;
+        }
+        Some("blockchain-info") => match rpc::call(get_params(&args).network, &RpcRequest::GetBlockchainInfo).await {
+            Ok(result) => match serde_json::from_value::<BlockchainInfo>(result) {
+                Ok(info) => info.print(),
+                Err(e) => eprintln!("Unexpected response: {e}"),
+            },
+            Err(e) => eprintln!("RPC error: {e}"),
+        },
+        Some("mempool-stats") => match rpc::call(get_params(&args).network, &RpcRequest::GetMempoolInfo).await {
+            Ok(result) => match serde_json::from_value::<MempoolStats>(result) {
+                Ok(stats) => stats.print(),
+                Err(e) => eprintln!("Unexpected response: {e}"),
+            },
+            Err(e) => eprintln!("RPC error: {e}"),
+        },
+        None | Some("mine") => {
+            let payout = match get_payout(&args) {
+                Ok(payout) => payout,
+                Err(e) => {
+                    eprintln!("Invalid payout: {e}");
+                    return;
+                }
+            };
+            let params = get_params(&args);
+            let mut node = Node::new(&params, data_dir(&params));
+            let mut miner = Miner::new(payout, node.handle());
+            if let Some(threads) = get_option(&args, "--threads") {
+                match threads.parse() {
+                    Ok(threads) => miner.set_threads(threads),
+                    Err(e) => {
+                        eprintln!("Invalid thread count {threads}: {e}");
+                        return;
+                    }
+                }
+            }
+            tokio::join!(node.send_recv_consensus(), miner.mine());
+        }
+        Some("pool") => {
+            let share_difficulty = match get_option(&args, "--share-difficulty").map(|value| value.parse()) {
+                None => stratum::DEFAULT_SHARE_DIFFICULTY,
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    eprintln!("Invalid share difficulty: {e}");
+                    return;
+                }
+            };
+            let server = match get_payout(&args).map(|payout| StratumServer::new(get_params(&args).network, payout, share_difficulty)) {
+                Ok(server) => server,
+                Err(e) => {
+                    eprintln!("Invalid payout: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = server.run().await {
+                eprintln!("Mining pool error: {e}");
+            }
+        }
+        Some("worker") => {
+            let pool = get_option(&args, "--pool").map(|pool| pool.to_string()).unwrap_or(format!("127.0.0.1:{}", get_params(&args).network.stratum_port()));
+            let name = get_option(&args, "--name").unwrap_or("worker").to_string();
+            let threads = match get_option(&args, "--threads").map(|value| value.parse()) {
+                None => pow::default_threads(),
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    eprintln!("Invalid thread count: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = stratum::run_worker(&pool, name, threads).await {
+                eprintln!("Worker error: {e}");
+            }
+        }
+        Some("generate") => {
+            let (Some(blocks), Some(address)) = (args.get(1), args.get(2)) else {
+                print_usage();
+                return;
+            };
+            let blocks = match blocks.parse() {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    eprintln!("Invalid block count {blocks}: {e}");
+                    return;
+                }
+            };
+            let address = match address.parse::<Address>() {
+                Ok(address) => address,
+                Err(e) => {
+                    eprintln!("Invalid address {address}: {e}");
+                    return;
+                }
+            };
+            match rpc::call(Network::Regtest, &RpcRequest::Generate { blocks, address }).await.and_then(|result| Ok(serde_json::from_value::<Vec<BlockHash>>(result)?)) {
+                Ok(hashes) => hashes.iter().for_each(|hash| println!("{hash}")),
+                Err(e) => eprintln!("RPC error: {e}"),
+            }
+        }
+        Some("benchmark") => {
+            let seconds = match get_option(&args, "--seconds").map(|value| value.parse()) {
+                None => BENCHMARK_SECS,
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    eprintln!("Invalid duration: {e}");
+                    return;
+                }
+            };
+            // doubling thread counts up to every available thread, unless a single count is asked for
+            let thread_counts: Vec<usize> = match get_option(&args, "--threads").map(|value| value.parse()) {
+                None => {
+                    let max = pow::default_threads();
+                    let mut counts: Vec<usize> = std::iter::successors(Some(1), |threads| Some(threads * 2)).take_while(|threads| *threads < max).collect();
+                    counts.push(max);
+                    counts
+                }
+                Some(Ok(value)) => vec![value],
+                Some(Err(e)) => {
+                    eprintln!("Invalid thread count: {e}");
+                    return;
+                }
+            };
+            println!("{:<10}{:<16}{}", "Threads", "Hashrate", "Per thread");
+            for threads in thread_counts {
+                let rate = pow::benchmark(threads, Duration::from_secs(seconds)).await;
+                println!("{:<10}{:<16}{}", threads, pow::fmt_hashrate(rate), pow::fmt_hashrate(rate / threads.max(1) as f64));
+            }
+        }
+        Some(command) => {
+            eprintln!("Unknown command: {command}");
+            print_usage();
+        }
+    }
+}
+
+// value following a flag, e.g. get_option(["mine", "--threads", "4"], "--threads") is Some("4")
+fn get_option<'a>(args: &'a Vec<String>, flag: &str) -> Option<&'a str> {
+    args.iter().position(|arg| arg == flag).and_then(|index| args.get(index + 1)).map(|value| value.as_str())
+}
+
+fn get_params(args: &Vec<String>) -> ChainParams {
+    if args.iter().any(|arg| arg == "--regtest") { ChainParams::regtest() } else { ChainParams::mainnet() }
+}
+
+// chains other than main keep their data in a subdirectory, so a regtest node never touches the main chain's files
+fn data_dir(params: &ChainParams) -> PathBuf {
+    if params.is_regtest() { PathBuf::from(DATA_DIR).join(params.network.name()) } else { PathBuf::from(DATA_DIR) }
+}
+
+// mined coins go to --payout, one address or a percentage split, or to the key in a --wallet file. either way
+// the payout is checked before anything starts up
+fn get_payout(args: &Vec<String>) -> Result<Payout, Box<dyn Error>> {
+    match (get_option(args, "--payout"), get_option(args, "--wallet")) {
+        (Some(_), Some(_)) => Err("--payout and --wallet can't be used together".into()),
+        (Some(spec), None) => Ok(spec.parse()?),
+        (None, Some(path)) => Payout::from_wallet(Path::new(path)),
+        (None, None) => Err("mining needs --payout or --wallet".into()),
+    }
+}
+
+fn print_usage() {
+    println!("Usage:");
+    println!("  mine (--payout PAYOUT | --wallet FILE) [--threads N] [--regtest]");
+    println!("                        run a node and mine on its chain (default), hashing on N threads");
+    println!("  node [--regtest]      run a node with the RPC server");
+    println!("  generate N ADDRESS    mine N blocks paying ADDRESS on the local regtest node, printing their hashes");
+    println!("  blockchain-info [--regtest]");
+    println!("                        print the height, tip and issued supply of the local node's chain");
+    println!("  mempool-stats [--regtest]");
+    println!("                        print mempool statistics from the local node");
+    println!("  benchmark [--threads N] [--seconds S]");
+    println!("                        measure the hashrate for S seconds on each thread count");
+    println!("  pool (--payout PAYOUT | --wallet FILE) [--share-difficulty N] [--regtest]");
+    println!("                        run a mining pool for the local node, shares are N times easier than blocks");
+    println!("  worker [--pool HOST:PORT] [--name NAME] [--threads N] [--regtest]");
+    println!("                        hash jobs from a mining pool");
+    println!();
+    println!("PAYOUT is an address, or addresses with percentages adding up to 100, e.g. ADDRESS:60,ADDRESS:40.");
+    println!("A wallet FILE holds a hex encoded secret key, blocks pay its public key.");
+    println!("--regtest runs on a local test chain where any hash is a valid block, with its own ports.");
+}
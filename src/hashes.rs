@@ -0,0 +1,106 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HexError {
+    InvalidLength(usize),
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexError::InvalidLength(len) => write!(f, "expected 64 hex characters, got {len}"),
+            HexError::InvalidCharacter(c) => write!(f, "invalid hex character {c:?}"),
+        }
+    }
+}
+
+impl std::error::Error for HexError {}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex_32(s: &str) -> Result<[u8;32], HexError> {
+    if s.len() != 64 {
+        return Err(HexError::InvalidLength(s.len()));
+    }
+    let mut bytes = [0u8;32];
+    let mut chars = s.chars();
+    for byte in bytes.iter_mut() {
+        let mut nibble = || {
+            let c = chars.next().unwrap();
+            c.to_digit(16).map(|d| d as u8).ok_or(HexError::InvalidCharacter(c))
+        };
+        *byte = (nibble()? << 4) | nibble()?;
+    }
+    Ok(bytes)
+}
+
+// every 32 byte identifier gets its own type so block hashes, txids and keys can't be mixed up,
+// all of them print as hex and are carried as hex strings in JSON
+macro_rules! hash_newtype {
+    ($name:ident) => {
+        #[derive(Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+        pub struct $name([u8;32]);
+
+        impl $name {
+            pub const ZERO: $name = $name([0;32]);
+
+            pub const fn from_bytes(bytes: [u8;32]) -> $name {
+                $name(bytes)
+            }
+
+            pub fn as_bytes(&self) -> &[u8;32] {
+                &self.0
+            }
+
+            pub fn to_bytes(self) -> [u8;32] {
+                self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", to_hex(&self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = HexError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                from_hex_32(s).map($name)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+hash_newtype!(BlockHash);
+hash_newtype!(Txid);
+hash_newtype!(Wtxid);
+hash_newtype!(MerkleRoot);
+// ed25519 public key that outputs are paid to
+hash_newtype!(Address);
@@ -1,12 +1,33 @@
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use crate::hashes::Txid;
 
 
 #[serde_as]
 #[derive(Clone, Copy, Hash, Serialize, Deserialize)]
 
 pub struct Input {
-    pub txid: [u8;32],
+    // txid of the transaction being spent, which stays stable when that transaction is re-signed
+    pub txid: Txid,
+    // index of the spent output within that transaction
+    pub vout: u32,
     #[serde_as(as = "serde_with::Bytes")]
     pub signature: [u8;64],
-}
\ No newline at end of file
+}
+
+impl Input {
+    pub fn is_coinbase(&self) -> bool {
+        self.txid == Txid::ZERO
+    }
+
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint { txid: self.txid, vout: self.vout }
+    }
+}
+
+// a single output of a transaction, which can be spent by exactly one input
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: Txid,
+    pub vout: u32,
+}
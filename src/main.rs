@@ -1,17 +1,29 @@
 use futures::{FutureExt, TryFutureExt};
 mod network;
+mod amount;
+mod cli;
 mod block;
+mod hashes;
 
 mod blockchain;
 mod mempool;
+mod orphans;
 mod transactions;
 mod input;
 mod output;
 mod miner;
+mod node;
+mod params;
+mod payout;
+mod pow;
+mod rpc;
+mod storage;
+mod stratum;
+#[cfg(test)]
+mod testing;
 
 
 #[tokio::main]
 async fn main() {
-    let mut miner = miner::Miner::new([0xbb;32]);
-    miner.mine().await;
+    cli::run(std::env::args().skip(1).collect()).await;
 }
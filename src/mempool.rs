@@ -1,88 +1,1085 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::block;
-use crate::blockchain::Blockchain;
-use crate::transactions::Tx;
+use libp2p::PeerId;
+use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::amount::{Amount, MAX_MONEY};
+use crate::block::Block;
+use crate::hashes::Txid;
+use crate::input::OutPoint;
+use crate::orphans::OrphanPool;
+use crate::output::Output;
+use crate::storage;
+use crate::transactions::{Tx, TxError};
 
 pub const MAX_MEMPOOL_SIZE: u32 = 150000;
+// transactions that haven't been mined after two weeks are dropped
+pub const MEMPOOL_EXPIRY_SECS: u64 = 14 * 24 * 60 * 60;
+// the minimum fee rate raised by evictions halves every twelve hours
+pub const MIN_FEE_HALFLIFE_SECS: u64 = 12 * 60 * 60;
+// file in the data directory the mempool is saved to
+pub const MEMPOOL_FILE: &str = "mempool.json";
+// events a subscriber can fall behind by before it starts missing them
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
 
-pub struct Mempool {
-    // key is txid, and value is mining fee / bytes
-    pub pool: BTreeSet<(u64,Tx)>,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalReason {
+    // mined in a connected block
+    Confirmed,
+    // spent the same outpoint as a transaction in a connected block, or spent the outputs of one a reorg took out of
+    // the chain without it coming back, or descends from one that did
+    Conflict,
+    // a replacement paying a higher fee took its place, or that of an ancestor
+    Replaced { by: Txid },
+    // pushed out by higher paying transactions when the pool was full
+    Evicted,
+    // sat in the pool for longer than MEMPOOL_EXPIRY_SECS
+    Expired,
+    // removed through Mempool::remove
+    Requested,
+}
+
+// lower bounds of the fee rate histogram buckets, in whole base units per byte
+pub const FEE_HISTOGRAM_BUCKETS: [u64; 16] = [0, 1, 2, 3, 5, 8, 10, 15, 20, 30, 50, 75, 100, 200, 500, 1000];
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeeRateBucket {
+    // fee rates in the bucket are at least this many base units per byte, and below the next bucket
+    pub min_fee_rate: u64,
+    pub count: usize,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MempoolStats {
+    pub count: usize,
+    pub size: u32,
+    pub total_fees: Amount,
+    // base units per byte, a fee rate below this isn't accepted right now
+    pub min_fee_rate: f64,
+    pub orphan_count: usize,
+    pub histogram: Vec<FeeRateBucket>,
+}
+
+impl MempoolStats {
+    pub fn print(&self) {
+        println!("Transactions: {}", self.count);
+        println!("Orphans: {}", self.orphan_count);
+        println!("Total size: {} Bytes", self.size.to_formatted_string(&Locale::en));
+        println!("Total fees: {}", self.total_fees);
+        println!("Minimum fee rate: {:.3} units/byte", self.min_fee_rate);
+        println!("\nFee rate (units/byte)   Transactions   Bytes");
+        for (index, bucket) in self.histogram.iter().enumerate() {
+            let range = match self.histogram.get(index + 1) {
+                Some(next) => format!("{} - {}", bucket.min_fee_rate, next.min_fee_rate),
+                None => format!("{}+", bucket.min_fee_rate),
+            };
+            println!("{:<24}{:<15}{}", range, bucket.count, bucket.size.to_formatted_string(&Locale::en));
+        }
+    }
+}
+
+// result of a successful admission
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Accepted {
+    pub txid: Txid,
+    pub fee: Amount,
+    // pooled transactions the new one replaced, including their descendants
+    pub replaced: Vec<Txid>,
+    // orphans admitted along with it, because it was the parent they were waiting for
+    pub readmitted: Vec<Txid>,
+}
+
+#[derive(Debug)]
+pub enum MempoolError {
+    AlreadyKnown,
+    // spends outpoints pooled transactions already spend, and can't replace them
+    Conflict(Vec<Txid>),
+    FeeTooLow { fee: Amount, required: Amount },
+    // accepted, but evicted again straight away because everything else in the full pool pays more
+    PoolFull,
+    InvalidTx(TxError),
+    // parents aren't known yet, the transaction waits in the orphan pool
+    Orphan(Vec<Txid>),
+    // parents aren't known yet, and the peer that sent it already has as many orphans waiting as it's allowed
+    OrphanLimit,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown => write!(f, "transaction already known"),
+            MempoolError::Conflict(txids) => write!(f, "conflicts with {} pooled transaction(s)", txids.len()),
+            MempoolError::FeeTooLow { fee, required } => write!(f, "fee {} is below the required {}", fee.fmt_base_units(), required.fmt_base_units()),
+            MempoolError::PoolFull => write!(f, "mempool full"),
+            MempoolError::InvalidTx(e) => write!(f, "invalid transaction: {e}"),
+            MempoolError::Orphan(parents) => write!(f, "missing {} parent transaction(s)", parents.len()),
+            MempoolError::OrphanLimit => write!(f, "missing parent transactions, and too many orphans from the same peer"),
+        }
+    }
+}
+
+impl Error for MempoolError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MempoolEvent {
+    Added { txid: Txid },
+    Removed { txid: Txid, reason: RemovalReason },
+}
+
+// rules a conflicting transaction has to meet to replace the pooled transactions it conflicts with
+#[derive(Clone)]
+pub struct ReplacementPolicy {
+    // the replacement has to pay at least this much more than everything it evicts
+    pub min_fee_increment: Amount,
+    // and also at least this fee rate on its own size on top of the evicted fees, so relaying it isn't free
+    pub incremental_fee_rate: u64,
+    // upper bound on conflicts plus their descendants evicted by one replacement
+    pub max_evictions: usize,
+}
+
+impl Default for ReplacementPolicy {
+    fn default() -> ReplacementPolicy {
+        ReplacementPolicy { min_fee_increment: Amount::from_base_units(1000), incremental_fee_rate: 1 << 16, max_evictions: 100 }
+    }
+}
+
+#[derive(Clone)]
+pub struct MempoolEntry {
+    pub tx: Tx,
+    // fee and size are computed once on admission, so the chain never has to be rescanned for pooled transactions
+    pub fee: Amount,
+    pub size: u32,
+    pub fee_rate: u64,
+    // manual adjustment to the fee used for ordering, the fee actually paid to the miner stays the same
+    pub fee_delta: i64,
+    // unix time the transaction entered the pool
+    pub time: u64,
+}
+
+impl MempoolEntry {
+    pub fn modified_fee(&self) -> Amount {
+        Self::apply_delta(self.fee, self.fee_delta)
+    }
+
+    fn apply_delta(fee: Amount, fee_delta: i64) -> Amount {
+        let units = fee.to_base_units().saturating_add_signed(fee_delta);
+        Amount::from_base_units(units).min(MAX_MONEY)
+    }
+}
+
+// what is written to disk for each pooled transaction, fees are recomputed against the utxo set on load
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry {
+    tx: Tx,
+    time: u64,
+    fee_delta: i64,
+}
+
+pub struct Mempool {
+    entries: HashMap<Txid, MempoolEntry>,
+    // entries ordered by fee rate, lowest first
+    by_fee_rate: BTreeSet<(u64, Txid)>,
+    // every outpoint spent by a pooled transaction, mapped to the txid spending it
+    spent: HashMap<OutPoint, Txid>,
+    total_size: u32,
+    replacement_policy: ReplacementPolicy,
+    orphans: OrphanPool,
+    // fee deltas set through prioritise_tx, kept even for transactions not in the pool yet
+    fee_deltas: HashMap<Txid, i64>,
+    // minimum fee rate after the last eviction, and when it was set
+    rolling_min_fee_rate: u64,
+    last_min_fee_update: u64,
+    events: broadcast::Sender<MempoolEvent>,
+    // events held back while an admission may still be rolled back, None when they're sent straight away
+    held_events: Option<Vec<MempoolEvent>>,
 }
 
 impl Mempool {
     pub fn new() -> Mempool {
-        Mempool { pool: BTreeSet::new()}
-    }
-
-    pub fn add_tx(&mut self, tx: Tx, chain: &Blockchain, utxos: &Vec<(u64, [u8;32])>) {
-        // if mempool has space, simply add tx to pool
-        if self.get_size() + tx.get_size() < MAX_MEMPOOL_SIZE {
-            self.verify(tx,chain,utxos);
-        }
-        // otherwise, if transaction would add higher fees to mempool, we add it
-        else {
-            let size = tx.get_size();
-            let mut sum = 0;
-            let mut insertion_index= 0;
-            for (index,(mfpb,ptx)) in self.pool.iter().enumerate(){
-                if sum < size{
-                    sum += ptx.get_size();
+        Self::with_replacement_policy(ReplacementPolicy::default())
+    }
+
+    pub fn with_replacement_policy(replacement_policy: ReplacementPolicy) -> Mempool {
+        Mempool { entries: HashMap::new(), by_fee_rate: BTreeSet::new(), spent: HashMap::new(), total_size: 0, replacement_policy, orphans: OrphanPool::new(),
+            fee_deltas: HashMap::new(), rolling_min_fee_rate: 0, last_min_fee_update: 0, events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            held_events: None }
+    }
+
+    // every change to the pool is announced to subscribers, a subscriber only sees events sent after subscribing
+    pub fn subscribe(&self) -> broadcast::Receiver<MempoolEvent> {
+        self.events.subscribe()
+    }
+
+    fn notify(&mut self, event: MempoolEvent) {
+        match self.held_events.as_mut() {
+            Some(held) => held.push(event),
+            // sending only fails when nobody is subscribed
+            None => { let _ = self.events.send(event); }
+        }
+    }
+
+    pub fn add_tx(&mut self, tx: Tx, utxos: &HashMap<OutPoint, Output>, peer: Option<PeerId>) -> Result<Accepted, MempoolError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.add_tx_at(tx, utxos, peer, now)
+    }
+
+    // time is when the transaction first arrived, which is earlier than now for transactions reloaded from disk
+    fn add_tx_at(&mut self, tx: Tx, utxos: &HashMap<OutPoint, Output>, peer: Option<PeerId>, time: u64) -> Result<Accepted, MempoolError> {
+        let txid = tx.txid;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.orphans.expire(now);
+        self.expire(now);
+
+        // transactions arriving before their parents wait in the orphan pool instead of being dropped
+        if self.contains(&txid) || self.orphans.contains(&txid) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        let missing_parents = self.get_missing_parents(&tx, utxos);
+        if !missing_parents.is_empty() {
+            if !self.orphans.add(tx, missing_parents.clone(), peer, now) {
+                return Err(MempoolError::OrphanLimit);
+            }
+            return Err(MempoolError::Orphan(missing_parents));
+        }
+
+        // while the pool is under pressure, transactions paying less than the rolling minimum aren't worth the space
+        let fee_delta = self.fee_deltas.get(&txid).copied().unwrap_or(0);
+        let fee = self.calc_fee(&tx, utxos).map_err(MempoolError::InvalidTx)?;
+        let min_fee_rate = self.get_min_fee_rate(now);
+        if Tx::fee_rate(MempoolEntry::apply_delta(fee, fee_delta), tx.get_size()) < min_fee_rate {
+            let required = Tx::fee_for_rate(min_fee_rate, tx.get_size());
+            return Err(MempoolError::FeeTooLow { fee, required });
+        }
+        let (fee, replaced) = self.verify(&tx, utxos)?;
+
+        // if trimming the pool back to size evicts the transaction straight away, everything it replaced or pushed
+        // out goes back, so a rejected transaction leaves the pool as it found it. subscribers only hear about the
+        // changes once the transaction is known to stay
+        let min_fee = (self.rolling_min_fee_rate, self.last_min_fee_update);
+        self.held_events = Some(vec![]);
+        let mut removed: Vec<MempoolEntry> = replaced.iter().filter_map(|rtxid| self.remove_entry(rtxid, RemovalReason::Replaced { by: txid })).collect();
+        self.insert(tx, fee, time);
+        removed.extend(self.trim_to_size(now));
+        if !self.contains(&txid) {
+            (self.rolling_min_fee_rate, self.last_min_fee_update) = min_fee;
+            for entry in removed.into_iter().filter(|entry| entry.tx.txid != txid) {
+                self.insert(entry.tx, entry.fee, entry.time);
+            }
+            self.held_events = None;
+            return Err(MempoolError::PoolFull);
+        }
+        for event in self.held_events.take().unwrap_or_default() {
+            self.notify(event);
+        }
+
+        // orphans that were waiting on this transaction get another attempt at admission. those still missing
+        // other parents go back to the orphan pool, invalid ones are dropped
+        let mut readmitted = vec![];
+        for orphan in self.orphans.take_children(&txid) {
+            if let Ok(accepted) = self.add_tx(orphan.tx, utxos, orphan.peer) {
+                readmitted.push(accepted.txid);
+                readmitted.extend(accepted.readmitted);
+            }
+        }
+        Ok(Accepted { txid, fee, replaced, readmitted })
+    }
+
+    // evicts the transaction with the lowest descendant package fee rate, together with its descendants, until
+    // the pool fits again. evicting whole packages means a parent is never removed while its children stay.
+    // returns the evicted entries
+    fn trim_to_size(&mut self, now: u64) -> Vec<MempoolEntry> {
+        let mut evicted = vec![];
+        while self.total_size > MAX_MEMPOOL_SIZE {
+            let lowest = self.entries.keys().map(|txid| {
+                let package: Vec<Txid> = std::iter::once(*txid).chain(self.get_descendants(txid)).collect();
+                let size: u32 = package.iter().map(|ptxid| self.entries[ptxid].size).sum();
+                let fee = Amount::checked_sum(package.iter().map(|ptxid| self.entries[ptxid].modified_fee())).unwrap_or(Amount::ZERO);
+                (Tx::fee_rate(fee, size), package)
+            }).min_by_key(|(rate, _)| *rate);
+            let Some((rate, package)) = lowest else { break };
+            for txid in package.iter() {
+                evicted.extend(self.remove_entry(txid, RemovalReason::Evicted));
+            }
+            // anything paying no more than what was just evicted would only be evicted again
+            let min_fee_rate = rate + self.replacement_policy.incremental_fee_rate;
+            if min_fee_rate > self.get_min_fee_rate(now) {
+                self.rolling_min_fee_rate = min_fee_rate;
+                self.last_min_fee_update = now;
+            }
+        }
+        evicted
+    }
+
+    // the rolling minimum decays exponentially once evictions stop, and drops back to zero when it gets small
+    pub fn get_min_fee_rate(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.last_min_fee_update) as f64;
+        let decayed = (self.rolling_min_fee_rate as f64 * 0.5f64.powf(elapsed / MIN_FEE_HALFLIFE_SECS as f64)) as u64;
+        if decayed < self.replacement_policy.incremental_fee_rate / 2 { 0 } else { decayed }
+    }
+
+    // removes transactions older than MEMPOOL_EXPIRY_SECS along with their descendants
+    fn expire(&mut self, now: u64) -> usize {
+        let expired: Vec<Txid> = self.entries.values()
+            .filter(|entry| entry.time + MEMPOOL_EXPIRY_SECS < now)
+            .map(|entry| entry.tx.txid).collect();
+        let mut removed = 0;
+        for txid in expired.iter() {
+            for descendant in self.get_descendants(txid) {
+                removed += self.remove_entry(&descendant, RemovalReason::Expired).is_some() as usize;
+            }
+            removed += self.remove_entry(txid, RemovalReason::Expired).is_some() as usize;
+        }
+        removed
+    }
+
+    // txids of parents that are neither confirmed nor pooled
+    fn get_missing_parents(&self, tx: &Tx, utxos: &HashMap<OutPoint, Output>) -> Vec<Txid> {
+        let mut missing: Vec<Txid> = vec![];
+        for input in tx.inputs.iter() {
+            if self.spent_output(&input.outpoint(), utxos).is_none() && !self.contains(&input.txid) && !missing.contains(&input.txid) {
+                missing.push(input.txid);
+            }
+        }
+        missing
+    }
+
+    // checks tx can be admitted without changing the pool, returning its fee and the transactions it would replace
+    fn verify(&self, tx: &Tx, utxos: &HashMap<OutPoint, Output>) -> Result<(Amount, Vec<Txid>), MempoolError> {
+        // the parent is pooled, but has no output at the index being spent
+        if let Some(input) = tx.inputs.iter().find(|input| self.spent_output(&input.outpoint(), utxos).is_none()){
+            return Err(MempoolError::InvalidTx(TxError::MissingInput(input.outpoint())));
+        }
+        // pool entries are keyed by txid, so a re-signed copy of a pooled transaction is the same entry
+        if self.contains(&tx.txid) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        // a transaction may not spend the same outpoint twice
+        if let Some(outpoint) = tx.find_duplicate_input() {
+            return Err(MempoolError::InvalidTx(TxError::DuplicateInput(outpoint)));
+        }
+        let fee = self.calc_fee(tx, utxos).map_err(MempoolError::InvalidTx)?;
+        let conflicts = self.get_conflicts(tx);
+        let mut replaced = vec![];
+        // outpoints already spent in the pool can only be taken over by a replacement paying enough more
+        if !conflicts.is_empty() {
+            replaced = self.check_replacement(tx, fee, &conflicts)?;
+        }
+        Ok((fee, replaced))
+    }
+
+    // inputs may spend confirmed outputs or outputs of transactions still in the pool
+    fn spent_output(&self, outpoint: &OutPoint, utxos: &HashMap<OutPoint, Output>) -> Option<Output> {
+        utxos.get(outpoint).cloned().or_else(|| {
+            self.entries.get(&outpoint.txid).and_then(|entry| entry.tx.outputs.get(outpoint.vout as usize).cloned())
+        })
+    }
+
+    fn calc_fee(&self, tx: &Tx, utxos: &HashMap<OutPoint, Output>) -> Result<Amount, TxError> {
+        tx.calc_mining_fee_from(|outpoint| self.spent_output(outpoint, utxos))
+    }
+
+    // returns every pooled transaction the replacement would evict, if it is allowed to replace its conflicts
+    fn check_replacement(&self, tx: &Tx, fee: Amount, conflicts: &Vec<Txid>) -> Result<Vec<Txid>, MempoolError> {
+        let policy = &self.replacement_policy;
+        let size = tx.get_size();
+        let fee_rate = Tx::fee_rate(fee, size);
+        // it has to pay a higher fee rate than each transaction it directly replaces
+        if let Some(highest_rate) = conflicts.iter().map(|txid| self.entries[txid].fee_rate).max().filter(|rate| *rate >= fee_rate) {
+            let required = Tx::fee_for_rate(highest_rate + 1, size);
+            return Err(MempoolError::FeeTooLow { fee, required });
+        }
+
+        let mut evicted: Vec<Txid> = vec![];
+        for txid in conflicts.iter() {
+            for evict in std::iter::once(*txid).chain(self.get_descendants(txid)) {
+                if !evicted.contains(&evict) {
+                    evicted.push(evict);
                 }
-                else {
-                    // only replaces transactions if the new transaction fees are higher than the ones it is replacing
-                    if tx.calc_mining_fee_per_byte(chain) > *mfpb {
-                        insertion_index = index + 1;
-                    }
-                    break;
+            }
+        }
+        if evicted.len() > policy.max_evictions {
+            return Err(MempoolError::Conflict(conflicts.clone()));
+        }
+        // a replacement spending the outputs of a transaction it evicts would remove its own parent
+        if tx.inputs.iter().any(|input| evicted.contains(&input.txid)) {
+            return Err(MempoolError::Conflict(conflicts.clone()));
+        }
+
+        // and it has to pay for the evicted transactions as well as its own relay
+        let evicted_fees = Amount::checked_sum(evicted.iter().map(|txid| self.entries[txid].fee)).ok_or(MempoolError::InvalidTx(TxError::AmountOverflow))?;
+        let relay_fee = Tx::fee_for_rate(policy.incremental_fee_rate, size);
+        let required = evicted_fees.checked_add(relay_fee.max(policy.min_fee_increment)).ok_or(MempoolError::InvalidTx(TxError::AmountOverflow))?;
+        if fee < required {
+            return Err(MempoolError::FeeTooLow { fee, required });
+        }
+        Ok(evicted)
+    }
+
+    fn insert(&mut self, tx: Tx, fee: Amount, time: u64) {
+        let size = tx.get_size();
+        let fee_delta = self.fee_deltas.get(&tx.txid).copied().unwrap_or(0);
+        let fee_rate = Tx::fee_rate(MempoolEntry::apply_delta(fee, fee_delta), size);
+        self.by_fee_rate.insert((fee_rate, tx.txid));
+        self.total_size += size;
+        tx.inputs.iter().for_each(|input| { self.spent.insert(input.outpoint(), tx.txid); });
+        self.notify(MempoolEvent::Added { txid: tx.txid });
+        self.entries.insert(tx.txid, MempoolEntry { tx, fee, size, fee_rate, fee_delta, time });
+    }
+
+    // called after block is connected: its transactions are confirmed, and anything in the pool spending the
+    // same outpoints can never be mined, so it is removed along with its descendants. orphans waiting on a
+    // confirmed transaction get another attempt at admission against utxos, the txids of those admitted are returned
+    pub fn remove_for_block(&mut self, block: &Block, utxos: &HashMap<OutPoint, Output>) -> Vec<Txid> {
+        for tx in block.transactions.iter() {
+            if self.remove_entry(&tx.txid, RemovalReason::Confirmed).is_some() {
+                self.fee_deltas.remove(&tx.txid);
+            }
+            for conflict in self.get_conflicts(tx) {
+                for descendant in self.get_descendants(&conflict) {
+                    self.remove_entry(&descendant, RemovalReason::Conflict);
+                }
+                self.remove_entry(&conflict, RemovalReason::Conflict);
+            }
+        }
+        let mut readmitted = vec![];
+        for tx in block.transactions.iter() {
+            for orphan in self.orphans.take_children(&tx.txid) {
+                if let Ok(accepted) = self.add_tx(orphan.tx, utxos, orphan.peer) {
+                    readmitted.push(accepted.txid);
+                    readmitted.extend(accepted.readmitted);
                 }
             }
-            // if the transaction fees are less than the minimum, they are not added to the chain
-            if insertion_index > 0 {
-                let to_remove: Vec<(u64,Tx)> = self.pool.iter().enumerate().filter(|(index,(_,_))|*index<insertion_index)
-                    .map(|(index,pair)| pair.clone()).collect();
-                for (pair) in to_remove.iter(){
-                    self.pool.remove(pair);
+        }
+        readmitted
+    }
+
+    // called after a reorg with the blocks that left the chain, oldest first. their transactions go back through
+    // admission against the new utxo set, skipping coinbases and anything the new chain confirmed. pooled
+    // transactions spending one that didn't make it back in are then removed with their descendants
+    pub fn readd_for_disconnect(&mut self, blocks: &Vec<Block>, confirmed: &HashSet<Txid>, utxos: &HashMap<OutPoint, Output>) {
+        for block in blocks.iter() {
+            for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase() && !confirmed.contains(&tx.txid)) {
+                let _ = self.add_tx(tx.clone(), utxos, None);
+            }
+        }
+        let unspendable: Vec<Txid> = self.entries.values()
+            .filter(|entry| entry.tx.inputs.iter().any(|input| self.spent_output(&input.outpoint(), utxos).is_none()))
+            .map(|entry| entry.tx.txid).collect();
+        for txid in unspendable {
+            for descendant in self.get_descendants(&txid) {
+                self.remove_entry(&descendant, RemovalReason::Conflict);
+            }
+            self.remove_entry(&txid, RemovalReason::Conflict);
+        }
+    }
+
+    // adds delta to the fee txid is ordered by, whether or not it is pooled yet
+    pub fn prioritise_tx(&mut self, txid: Txid, delta: i64) {
+        let fee_delta = self.fee_deltas.entry(txid).or_insert(0);
+        *fee_delta = fee_delta.saturating_add(delta);
+        let fee_delta = *fee_delta;
+        if let Some(entry) = self.entries.get_mut(&txid) {
+            self.by_fee_rate.remove(&(entry.fee_rate, txid));
+            entry.fee_delta = fee_delta;
+            entry.fee_rate = Tx::fee_rate(entry.modified_fee(), entry.size);
+            self.by_fee_rate.insert((entry.fee_rate, txid));
+        }
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let persisted: Vec<PersistedEntry> = self.entries.values().map(|entry| {
+            PersistedEntry { tx: entry.tx.clone(), time: entry.time, fee_delta: entry.fee_delta }
+        }).collect();
+        fs::create_dir_all(data_dir)?;
+        storage::write_atomic(&data_dir.join(MEMPOOL_FILE), &serde_json::to_string(&persisted)?)?;
+        Ok(())
+    }
+
+    // reloads a saved mempool, every transaction goes through admission again against the current utxo set,
+    // returns how many were accepted
+    pub fn load(&mut self, data_dir: &Path, utxos: &HashMap<OutPoint, Output>) -> Result<usize, Box<dyn Error>> {
+        let path = data_dir.join(MEMPOOL_FILE);
+        if !path.exists() {
+            return Ok(0);
+        }
+        let mut persisted: Vec<PersistedEntry> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let before = self.len();
+        // parents arrived before their children, so loading oldest first keeps packages together
+        persisted.sort_by_key(|entry| entry.time);
+        for entry in persisted {
+            if entry.fee_delta != 0 {
+                self.prioritise_tx(entry.tx.txid, entry.fee_delta);
+            }
+            let _ = self.add_tx_at(entry.tx, utxos, None, entry.time);
+        }
+        Ok(self.len().saturating_sub(before))
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn remove(&mut self, txid: &Txid) -> Option<MempoolEntry> {
+        self.remove_entry(txid, RemovalReason::Requested)
+    }
+
+    fn remove_entry(&mut self, txid: &Txid, reason: RemovalReason) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        self.notify(MempoolEvent::Removed { txid: *txid, reason });
+        self.by_fee_rate.remove(&(entry.fee_rate, entry.tx.txid));
+        self.total_size -= entry.size;
+        entry.tx.inputs.iter().for_each(|input| { self.spent.remove(&input.outpoint()); });
+        Some(entry)
+    }
+
+    // txids of pooled transactions spending any of the same outpoints as tx
+    pub fn get_conflicts(&self, tx: &Tx) -> Vec<Txid> {
+        let conflicts: HashSet<Txid> = tx.inputs.iter()
+            .filter_map(|input| self.spent.get(&input.outpoint()))
+            .filter(|txid| **txid != tx.txid)
+            .copied().collect();
+        conflicts.into_iter().collect()
+    }
+
+    // every pooled transaction that spends an output of txid, directly or through other pooled transactions
+    pub fn get_descendants(&self, txid: &Txid) -> Vec<Txid> {
+        let mut descendants: Vec<Txid> = vec![];
+        let mut to_visit = vec![*txid];
+        while let Some(parent) = to_visit.pop() {
+            let Some(entry) = self.entries.get(&parent) else { continue };
+            for vout in 0..entry.tx.outputs.len() as u32 {
+                if let Some(child) = self.spent.get(&OutPoint { txid: parent, vout }) {
+                    if !descendants.contains(child) {
+                        descendants.push(*child);
+                        to_visit.push(*child);
+                    }
                 }
-                self.verify(tx,chain,utxos);
             }
         }
+        descendants
+    }
+
+    // txid and its ancestors that aren't in selected yet, ordered so that parents always come before their children
+    fn unselected_package(&self, txid: &Txid, selected: &HashSet<Txid>) -> Vec<Txid> {
+        let mut package = vec![];
+        self.visit_parents_first(txid, selected, &mut package);
+        package
     }
 
-    fn verify(&mut self,tx: Tx, chain: &Blockchain, utxos: &Vec<(u64, [u8;32])>) {
-        let mut unspent_txids = vec![];
-        utxos.iter().for_each(|(_,tx)| unspent_txids.push(*tx));
-        if tx.inputs.iter().any(|input| !unspent_txids.contains(&input.txid)){
-            println!("I should totaly handle this erorr");
+    fn visit_parents_first(&self, txid: &Txid, selected: &HashSet<Txid>, package: &mut Vec<Txid>) {
+        if selected.contains(txid) || package.contains(txid) {
+            return;
         }
-        else {
-            self.pool.insert((tx.calc_mining_fee_per_byte(chain), tx));
+        let Some(entry) = self.entries.get(txid) else { return };
+        for input in entry.tx.inputs.iter() {
+            self.visit_parents_first(&input.txid, selected, package);
         }
+        package.push(*txid);
     }
-    pub fn calc_valid_tx_pool_and_fees(&mut self, chain: &Blockchain) -> (Vec<Tx>,u64) {
-        let mut total_fees: u64 = 0;
+
+    // highest fee rate first
+    pub fn iter_by_fee_rate(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.by_fee_rate.iter().rev().map(|(_, txid)| &self.entries[txid])
+    }
+
+    // picks transactions by ancestor package fee rate, so a child paying a high fee pulls its low fee parents
+    // into the block with it, and a transaction is never included without the pooled parents it spends.
+    // max_size is what's left of the block after the header and coinbase. the pool itself is left untouched,
+    // transactions only leave it once the block is connected
+    pub fn calc_valid_tx_pool_and_fees(&self, max_size: u32) -> (Vec<Tx>,Amount) {
+        let mut total_fees = Amount::ZERO;
         let mut transactions = vec![];
         let mut tx_pool_size: u32 = 0;
-        let mut removal_pairs = vec![];
+        let mut selected = HashSet::new();
+        let mut spent = HashSet::new();
 
-        self.pool.iter().rev().for_each(|(fee,ptx)|{
-            // for each transaction in pool, we calculate mining fees
-            if ptx.get_size() + tx_pool_size + 228 < block::MAX_BLOCK_SIZE {
-                transactions.push(ptx.clone());
-                tx_pool_size += ptx.get_size();
-                total_fees += ptx.calc_mining_fee(chain);
-                removal_pairs.push((*fee,ptx.clone()));
+        loop {
+            let mut best: Option<(u64, Vec<Txid>, u32, Amount)> = None;
+            for txid in self.entries.keys().filter(|txid| !selected.contains(*txid)) {
+                let package = self.unselected_package(txid, &selected);
+                let package_size: u32 = package.iter().map(|ptxid| self.entries[ptxid].size).sum();
+                if package_size + tx_pool_size > max_size {
+                    continue;
+                }
+                // a template never contains two transactions spending the same outpoint
+                if package.iter().flat_map(|ptxid| self.entries[ptxid].tx.inputs.iter()).any(|input| spent.contains(&input.outpoint())) {
+                    continue;
+                }
+                // packages that would overflow the total fees are left out
+                let Some(package_fee) = Amount::checked_sum(package.iter().map(|ptxid| self.entries[ptxid].fee)) else { continue };
+                if total_fees.checked_add(package_fee).is_none() {
+                    continue;
+                }
+                let modified_fee = Amount::checked_sum(package.iter().map(|ptxid| self.entries[ptxid].modified_fee())).unwrap_or(package_fee);
+                let package_rate = Tx::fee_rate(modified_fee, package_size);
+                if best.as_ref().map_or(true, |(best_rate, ..)| package_rate > *best_rate) {
+                    best = Some((package_rate, package, package_size, package_fee));
+                }
             }
-        });
-        for pair in removal_pairs.iter(){
-            self.pool.remove(pair);
+
+            let Some((_, package, package_size, package_fee)) = best else { break };
+            for ptxid in package {
+                let entry = &self.entries[&ptxid];
+                entry.tx.inputs.iter().for_each(|input| { spent.insert(input.outpoint()); });
+                transactions.push(entry.tx.clone());
+                selected.insert(ptxid);
+            }
+            tx_pool_size += package_size;
+            total_fees = total_fees.checked_add(package_fee).unwrap();
         }
         (transactions,total_fees)
     }
 
-    pub fn get_size(&self) -> u32 { self.pool.iter().map(|(_,tx)|tx.get_size()).sum() }
+    pub fn get_stats(&self) -> MempoolStats {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut histogram: Vec<FeeRateBucket> = FEE_HISTOGRAM_BUCKETS.iter()
+            .map(|min_fee_rate| FeeRateBucket { min_fee_rate: *min_fee_rate, count: 0, size: 0 }).collect();
+        // entries come highest fee rate first, so the bucket they fall in only ever moves down
+        let mut buckets = histogram.iter_mut().rev().peekable();
+        for entry in self.iter_by_fee_rate() {
+            // fee rates are stored with 16 fractional bits, buckets are in whole units
+            let units_per_byte = entry.fee_rate >> 16;
+            while buckets.next_if(|bucket| bucket.min_fee_rate > units_per_byte).is_some() {}
+            if let Some(bucket) = buckets.peek_mut() {
+                bucket.count += 1;
+                bucket.size += entry.size;
+            }
+        }
+        MempoolStats {
+            count: self.len(),
+            size: self.get_size(),
+            total_fees: Amount::checked_sum(self.entries.values().map(|entry| entry.fee)).unwrap_or(MAX_MONEY),
+            min_fee_rate: self.get_min_fee_rate(now) as f64 / 65536.0,
+            orphan_count: self.orphan_count(),
+            histogram,
+        }
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn orphan_count(&self) -> usize { self.orphans.len() }
+
+    pub fn get_size(&self) -> u32 { self.total_size }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Signer;
+
+    use super::*;
+    use crate::block;
+    use crate::hashes::{BlockHash, MerkleRoot};
+    use crate::input::Input;
+    use crate::orphans::MAX_ORPHANS_PER_PEER;
+    use crate::params::ChainParams;
+    use crate::testing;
+
+    // a confirmed transaction with that many outputs paying address, and the utxo set holding them
+    fn funding(address: crate::hashes::Address, outputs: u32) -> (Tx, HashMap<OutPoint, Output>) {
+        let outputs: Vec<Output> = (0..outputs).map(|_| Output { amount: Amount::from_base_units(1_000_000), address }).collect();
+        let tx = Tx { txid: Txid::from_bytes([9; 32]), inputs: vec![], outputs };
+        let utxos = tx.outputs.iter().enumerate().map(|(vout, output)| (OutPoint { txid: tx.txid, vout: vout as u32 }, output.clone())).collect();
+        (tx, utxos)
+    }
+
+    // like testing::spend, but the amount is split into that many equal outputs
+    fn split(key: &ed25519_dalek::SigningKey, parent: &Tx, vout: u32, to: crate::hashes::Address, outputs: u64, fee: Amount) -> Tx {
+        let amount = Amount::from_base_units(parent.outputs[vout as usize].amount.checked_sub(fee).unwrap().to_base_units() / outputs);
+        testing::pay(key, parent, vout, (0..outputs).map(|_| Output { amount, address: to }).collect())
+    }
+
+    #[test]
+    fn entries_are_kept_by_txid_and_listed_by_fee_rate() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 3);
+        let mut pool = Mempool::new();
+        let txs: Vec<Tx> = [2000, 5000, 1000].iter().enumerate()
+            .map(|(vout, fee)| testing::spend(&key, &funding, vout as u32, to, Amount::from_base_units(*fee))).collect();
+        txs.iter().for_each(|tx| { pool.add_tx(tx.clone(), &utxos, None).unwrap(); });
+
+        let entry = pool.get(&txs[1].txid).unwrap();
+        assert_eq!((entry.fee, entry.size), (Amount::from_base_units(5000), txs[1].get_size()));
+        let by_fee_rate: Vec<Txid> = pool.iter_by_fee_rate().map(|entry| entry.tx.txid).collect();
+        assert_eq!(by_fee_rate, vec![txs[1].txid, txs[0].txid, txs[2].txid]);
+        // prioritising moves an entry without it being added again
+        pool.prioritise_tx(txs[2].txid, 10000);
+        assert_eq!(pool.iter_by_fee_rate().next().unwrap().tx.txid, txs[2].txid);
+
+        assert_eq!(pool.remove(&txs[1].txid).unwrap().tx.txid, txs[1].txid);
+        assert!(!pool.contains(&txs[1].txid) && pool.get(&txs[1].txid).is_none() && pool.remove(&txs[1].txid).is_none());
+        assert_eq!(pool.iter_by_fee_rate().count(), 2);
+        assert_eq!(pool.get_size(), txs[0].get_size() + txs[2].get_size());
+        // the outpoint it spent is free again, so spending it isn't a replacement
+        let again = testing::spend(&key, &funding, 1, testing::address(&key), Amount::from_base_units(3000));
+        assert!(pool.add_tx(again, &utxos, None).unwrap().replaced.is_empty());
+    }
+
+    #[test]
+    fn replacement_evicted_by_a_full_pool_restores_what_it_replaced() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 37);
+        let mut pool = Mempool::new();
+        let cheap = testing::spend(&key, &funding, 0, to, Amount::from_base_units(1000));
+        pool.add_tx(cheap.clone(), &utxos, None).unwrap();
+        // fills the pool to just under MAX_MEMPOOL_SIZE with transactions paying ten times the rate
+        for vout in 1..37 {
+            pool.add_tx(split(&key, &funding, vout, to, 100, Amount::from_base_units(250000)), &utxos, None).unwrap();
+        }
 
+        // pays enough to replace cheap, but is bigger and pays a lower rate than anything else left
+        let replacement = split(&key, &funding, 0, to, 30, Amount::from_base_units(8000));
+        let size = pool.get_size();
+        assert!(size - cheap.get_size() + replacement.get_size() > MAX_MEMPOOL_SIZE);
+        let mut events = pool.subscribe();
+        assert!(matches!(pool.add_tx(replacement.clone(), &utxos, None), Err(MempoolError::PoolFull)));
+        // subscribers never hear of the replacement or of what it briefly pushed out
+        assert!(matches!(events.try_recv(), Err(broadcast::error::TryRecvError::Empty)));
 
-}
\ No newline at end of file
+        assert!(pool.contains(&cheap.txid));
+        assert!(!pool.contains(&replacement.txid));
+        assert_eq!(pool.len(), 37);
+        assert_eq!(pool.get_size(), size);
+        assert_eq!(pool.get_min_fee_rate(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()), 0);
+    }
+
+    // only the fee rate rule is left when replacements don't have to pay for anything else
+    fn rate_only_policy() -> ReplacementPolicy {
+        ReplacementPolicy { min_fee_increment: Amount::ZERO, incremental_fee_rate: 0, max_evictions: 100 }
+    }
+
+    #[test]
+    fn replacement_pays_a_higher_fee_rate_than_what_it_replaces() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::with_replacement_policy(rate_only_policy());
+        let original = testing::spend(&key, &funding, 0, to, Amount::from_base_units(1000));
+        pool.add_tx(original.clone(), &utxos, None).unwrap();
+
+        // a bigger transaction paying a higher fee, but at a lower rate
+        let lower_rate = split(&key, &funding, 0, to, 2, Amount::from_base_units(1200));
+        assert!(lower_rate.get_size() > original.get_size());
+        assert!(matches!(pool.add_tx(lower_rate, &utxos, None), Err(MempoolError::FeeTooLow { .. })));
+        let higher_rate = split(&key, &funding, 0, to, 2, Amount::from_base_units(1300));
+        assert_eq!(pool.add_tx(higher_rate.clone(), &utxos, None).unwrap().replaced, vec![original.txid]);
+        assert!(!pool.contains(&original.txid) && pool.contains(&higher_rate.txid));
+    }
+
+    #[test]
+    fn replacement_pays_the_minimum_increment() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::new();
+        let original = testing::spend(&key, &funding, 0, to, Amount::from_base_units(1000));
+        pool.add_tx(original.clone(), &utxos, None).unwrap();
+
+        // a higher rate alone isn't enough, the replacement has to add min_fee_increment to what it evicts
+        let short = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1999));
+        match pool.add_tx(short, &utxos, None) {
+            Err(MempoolError::FeeTooLow { required, .. }) => assert_eq!(required, Amount::from_base_units(2000)),
+            _ => panic!("expected FeeTooLow"),
+        }
+        let enough = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(2000));
+        assert_eq!(pool.add_tx(enough, &utxos, None).unwrap().replaced, vec![original.txid]);
+    }
+
+    #[test]
+    fn replacement_evicts_the_descendants_of_what_it_replaces() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::new();
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1000));
+        let child = testing::spend(&key, &parent, 0, to, Amount::from_base_units(1000));
+        pool.add_tx(parent.clone(), &utxos, None).unwrap();
+        pool.add_tx(child.clone(), &utxos, None).unwrap();
+
+        // the replacement pays for the child as well as the parent it conflicts with
+        let short = testing::spend(&key, &funding, 0, to, Amount::from_base_units(2999));
+        assert!(matches!(pool.add_tx(short, &utxos, None), Err(MempoolError::FeeTooLow { .. })));
+        let replacement = testing::spend(&key, &funding, 0, to, Amount::from_base_units(3000));
+        let mut replaced = pool.add_tx(replacement, &utxos, None).unwrap().replaced;
+        replaced.sort();
+        let mut expected = vec![parent.txid, child.txid];
+        expected.sort();
+        assert_eq!(replaced, expected);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn replacement_evicting_too_many_transactions_is_refused() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::with_replacement_policy(ReplacementPolicy { max_evictions: 1, ..ReplacementPolicy::default() });
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1000));
+        let child = testing::spend(&key, &parent, 0, to, Amount::from_base_units(1000));
+        pool.add_tx(parent.clone(), &utxos, None).unwrap();
+        pool.add_tx(child.clone(), &utxos, None).unwrap();
+
+        let replacement = testing::spend(&key, &funding, 0, to, Amount::from_base_units(100_000));
+        assert!(matches!(pool.add_tx(replacement, &utxos, None), Err(MempoolError::Conflict(conflicts)) if conflicts == vec![parent.txid]));
+        assert!(pool.contains(&parent.txid) && pool.contains(&child.txid));
+    }
+
+    #[test]
+    fn replacement_spending_a_transaction_it_evicts_is_refused() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::new();
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1000));
+        pool.add_tx(parent.clone(), &utxos, None).unwrap();
+
+        // spends the coin the parent spends, evicting it, and the parent's own output
+        let outputs = vec![Output { amount: Amount::from_base_units(1_900_000), address: to }];
+        let mut inputs = vec![Input { txid: funding.txid, vout: 0, signature: [0; 64] }, Input { txid: parent.txid, vout: 0, signature: [0; 64] }];
+        for input in inputs.iter_mut() {
+            input.signature = key.sign(&Tx::sighash(&input.outpoint(), &outputs)).to_bytes();
+        }
+        let replacement = Tx { txid: Tx::generate_txid(&inputs, &outputs), inputs, outputs };
+        assert!(matches!(pool.add_tx(replacement, &utxos, None), Err(MempoolError::Conflict(_))));
+        assert!(pool.contains(&parent.txid));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn high_fee_child_pulls_its_parent_into_the_template() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 2);
+        let mut pool = Mempool::new();
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1000));
+        let child = testing::spend(&key, &parent, 0, to, Amount::from_base_units(50_000));
+        // pays a higher rate than the parent, but lower than the parent and child together
+        let other = testing::spend(&key, &funding, 1, to, Amount::from_base_units(5000));
+        pool.add_tx(parent.clone(), &utxos, None).unwrap();
+        pool.add_tx(child.clone(), &utxos, None).unwrap();
+        pool.add_tx(other.clone(), &utxos, None).unwrap();
+        assert_eq!(pool.len(), 3);
+
+        let (transactions, fees) = pool.calc_valid_tx_pool_and_fees(block::MAX_BLOCK_SIZE);
+        let order: Vec<Txid> = transactions.iter().map(|tx| tx.txid).collect();
+        assert_eq!(order, vec![parent.txid, child.txid, other.txid]);
+        assert_eq!(fees, Amount::from_base_units(56_000));
+
+        // with only room for two, the package beats the transaction paying more than the parent alone
+        let (transactions, fees) = pool.calc_valid_tx_pool_and_fees(parent.get_size() + child.get_size());
+        let order: Vec<Txid> = transactions.iter().map(|tx| tx.txid).collect();
+        assert_eq!(order, vec![parent.txid, child.txid]);
+        assert_eq!(fees, Amount::from_base_units(51_000));
+
+        // a child never goes in without room for its parent
+        let (transactions, _) = pool.calc_valid_tx_pool_and_fees(child.get_size());
+        assert!(transactions.iter().all(|tx| tx.txid != child.txid));
+    }
+
+    #[test]
+    fn full_pool_evicts_the_lowest_paying_package_with_its_descendants() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 39);
+        let mut pool = Mempool::new();
+        // the child alone pays more than the parent and child together, so the parent's package goes first
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(500));
+        let child = testing::spend(&key, &parent, 0, to, Amount::from_base_units(1500));
+        pool.add_tx(parent.clone(), &utxos, None).unwrap();
+        pool.add_tx(child.clone(), &utxos, None).unwrap();
+        for vout in 1..37 {
+            pool.add_tx(split(&key, &funding, vout, to, 100, Amount::from_base_units(250000)), &utxos, None).unwrap();
+        }
+
+        // pushes the pool over MAX_MEMPOOL_SIZE, by less than the parent and child take up together
+        let overflow = split(&key, &funding, 37, to, 20, Amount::from_base_units(500000));
+        let over = pool.get_size() + overflow.get_size() - MAX_MEMPOOL_SIZE;
+        assert!(over > 0 && over <= parent.get_size() + child.get_size());
+        let mut events = pool.subscribe();
+        pool.add_tx(overflow.clone(), &utxos, None).unwrap();
+        assert!(!pool.contains(&parent.txid) && !pool.contains(&child.txid));
+        assert_eq!(received(&mut events), vec![
+            MempoolEvent::Added { txid: overflow.txid },
+            MempoolEvent::Removed { txid: parent.txid, reason: RemovalReason::Evicted },
+            MempoolEvent::Removed { txid: child.txid, reason: RemovalReason::Evicted },
+        ]);
+        assert!(pool.contains(&overflow.txid));
+        assert_eq!(pool.len(), 37);
+
+        // paying no more than what was evicted isn't worth admitting until the minimum decays
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let package_rate = Tx::fee_rate(Amount::from_base_units(2000), parent.get_size() + child.get_size());
+        assert_eq!(pool.get_min_fee_rate(now), package_rate + ReplacementPolicy::default().incremental_fee_rate);
+        let cheap = testing::spend(&key, &funding, 38, to, Amount::from_base_units(1000));
+        assert!(matches!(pool.add_tx(cheap, &utxos, None), Err(MempoolError::FeeTooLow { .. })));
+    }
+
+    #[test]
+    fn minimum_fee_rate_halves_every_halflife_then_drops_to_zero() {
+        let mut pool = Mempool::new();
+        let incremental = ReplacementPolicy::default().incremental_fee_rate;
+        pool.rolling_min_fee_rate = 4 * incremental;
+        pool.last_min_fee_update = 1000;
+        assert_eq!(pool.get_min_fee_rate(1000), 4 * incremental);
+        assert_eq!(pool.get_min_fee_rate(1000 + MIN_FEE_HALFLIFE_SECS), 2 * incremental);
+        assert_eq!(pool.get_min_fee_rate(1000 + 3 * MIN_FEE_HALFLIFE_SECS), incremental / 2);
+        // below half the incremental rate it's not worth keeping
+        assert_eq!(pool.get_min_fee_rate(1000 + 4 * MIN_FEE_HALFLIFE_SECS), 0);
+    }
+
+    #[test]
+    fn expired_transactions_leave_with_their_descendants() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 2);
+        let mut pool = Mempool::new();
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1000));
+        let child = testing::spend(&key, &parent, 0, to, Amount::from_base_units(1000));
+        let other = testing::spend(&key, &funding, 1, to, Amount::from_base_units(1000));
+        for tx in [&parent, &child, &other] {
+            pool.add_tx(tx.clone(), &utxos, None).unwrap();
+        }
+
+        // the child arrived later, but can't stay once its parent is gone
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        pool.entries.get_mut(&parent.txid).unwrap().time = now - MEMPOOL_EXPIRY_SECS - 1;
+        assert_eq!(pool.expire(now), 2);
+        assert!(!pool.contains(&parent.txid) && !pool.contains(&child.txid));
+        assert!(pool.contains(&other.txid));
+        assert_eq!(pool.expire(now), 0);
+    }
+
+    fn received(events: &mut broadcast::Receiver<MempoolEvent>) -> Vec<MempoolEvent> {
+        std::iter::from_fn(|| events.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn subscribers_hear_of_every_change_and_its_reason() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 4);
+        let mut pool = Mempool::new();
+        let mut events = pool.subscribe();
+        let fee = Amount::from_base_units(1000);
+
+        let original = testing::spend(&key, &funding, 0, to, fee);
+        pool.add_tx(original.clone(), &utxos, None).unwrap();
+        assert_eq!(received(&mut events), vec![MempoolEvent::Added { txid: original.txid }]);
+        let replacement = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(2000));
+        pool.add_tx(replacement.clone(), &utxos, None).unwrap();
+        assert_eq!(received(&mut events), vec![
+            MempoolEvent::Removed { txid: original.txid, reason: RemovalReason::Replaced { by: replacement.txid } },
+            MempoolEvent::Added { txid: replacement.txid },
+        ]);
+
+        let txs: Vec<Tx> = (1..4).map(|vout| testing::spend(&key, &funding, vout, to, fee)).collect();
+        txs.iter().for_each(|tx| { pool.add_tx(tx.clone(), &utxos, None).unwrap(); });
+        received(&mut events);
+
+        // a block confirming the replacement and double spending the first of the others
+        let double_spend = testing::spend(&key, &funding, 1, testing::address(&key), fee);
+        let block = Block { index: 1, hash: BlockHash::ZERO, previous_hash: BlockHash::ZERO, time: 0, target: 0, nonce: 0,
+            merkle_root: MerkleRoot::ZERO, witness_root: MerkleRoot::ZERO, transactions: vec![replacement.clone(), double_spend] };
+        pool.remove_for_block(&block, &utxos);
+        assert_eq!(received(&mut events), vec![
+            MempoolEvent::Removed { txid: replacement.txid, reason: RemovalReason::Confirmed },
+            MempoolEvent::Removed { txid: txs[0].txid, reason: RemovalReason::Conflict },
+        ]);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        pool.entries.get_mut(&txs[1].txid).unwrap().time = now - MEMPOOL_EXPIRY_SECS - 1;
+        pool.expire(now);
+        assert_eq!(received(&mut events), vec![MempoolEvent::Removed { txid: txs[1].txid, reason: RemovalReason::Expired }]);
+        pool.remove(&txs[2].txid);
+        assert_eq!(received(&mut events), vec![MempoolEvent::Removed { txid: txs[2].txid, reason: RemovalReason::Requested }]);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn paying_the_reported_required_fee_is_enough() {
+        let key = testing::key(1);
+        let to = testing::address(&testing::key(2));
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::new();
+        pool.add_tx(testing::spend(&key, &funding, 0, to, Amount::from_base_units(1000)), &utxos, None).unwrap();
+
+        // first below the fee rate of the original, then below the required increment
+        let mut fee = Amount::from_base_units(1000);
+        for _ in 0..2 {
+            let replacement = testing::spend(&key, &funding, 0, testing::address(&key), fee);
+            match pool.add_tx(replacement, &utxos, None) {
+                Err(MempoolError::FeeTooLow { required, .. }) => {
+                    assert!(required > fee);
+                    fee = required;
+                }
+                Err(e) => panic!("expected FeeTooLow, got {e}"),
+                Ok(_) => panic!("replacement paying {fee} accepted"),
+            }
+        }
+        assert_eq!(fee, Amount::from_base_units(2000));
+        pool.add_tx(testing::spend(&key, &funding, 0, testing::address(&key), fee), &utxos, None).unwrap();
+    }
+
+    #[test]
+    fn orphans_are_admitted_with_their_parent() {
+        let key = testing::key(1);
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::new();
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1000));
+        let child = testing::spend(&key, &parent, 0, testing::address(&testing::key(2)), Amount::from_base_units(1000));
+        assert!(matches!(pool.add_tx(child.clone(), &utxos, None), Err(MempoolError::Orphan(parents)) if parents == vec![parent.txid]));
+        let accepted = pool.add_tx(parent, &utxos, None).unwrap();
+        assert_eq!(accepted.readmitted, vec![child.txid]);
+        assert!(pool.contains(&child.txid));
+        assert_eq!(pool.orphan_count(), 0);
+    }
+
+    #[test]
+    fn orphans_are_admitted_when_their_parent_is_confirmed() {
+        let key = testing::key(1);
+        let (funding, mut utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::new();
+        let parent = testing::spend(&key, &funding, 0, testing::address(&key), Amount::from_base_units(1000));
+        let child = testing::spend(&key, &parent, 0, testing::address(&testing::key(2)), Amount::from_base_units(1000));
+        assert!(matches!(pool.add_tx(child.clone(), &utxos, None), Err(MempoolError::Orphan(_))));
+
+        // the parent never went through the pool, it only shows up in a block
+        let block = testing::block_on(&ChainParams::regtest().genesis_block(), Amount::ZERO, testing::address(&key), vec![parent.clone()]);
+        utxos.remove(&parent.inputs[0].outpoint());
+        utxos.insert(OutPoint { txid: parent.txid, vout: 0 }, parent.outputs[0].clone());
+        assert_eq!(pool.remove_for_block(&block, &utxos), vec![child.txid]);
+        assert!(pool.contains(&child.txid));
+        assert_eq!(pool.orphan_count(), 0);
+    }
+
+    #[test]
+    fn orphans_over_the_per_peer_limit_are_refused() {
+        let key = testing::key(1);
+        let (funding, utxos) = funding(testing::address(&key), 1);
+        let mut pool = Mempool::new();
+        let peer = PeerId::random();
+        let parent = split(&key, &funding, 0, testing::address(&key), MAX_ORPHANS_PER_PEER as u64 + 1, Amount::from_base_units(1000));
+        for vout in 0..MAX_ORPHANS_PER_PEER as u32 {
+            let orphan = testing::spend(&key, &parent, vout, testing::address(&key), Amount::from_base_units(1000));
+            assert!(matches!(pool.add_tx(orphan, &utxos, Some(peer)), Err(MempoolError::Orphan(_))));
+        }
+        let over = testing::spend(&key, &parent, MAX_ORPHANS_PER_PEER as u32, testing::address(&key), Amount::from_base_units(1000));
+        assert!(matches!(pool.add_tx(over.clone(), &utxos, Some(peer)), Err(MempoolError::OrphanLimit)));
+        assert_eq!(pool.orphan_count(), MAX_ORPHANS_PER_PEER);
+        // other peers still have room
+        assert!(matches!(pool.add_tx(over, &utxos, Some(PeerId::random())), Err(MempoolError::Orphan(_))));
+    }
+}
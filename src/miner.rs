@@ -1,211 +1,269 @@
 use tokio::sync::Mutex;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use libp2p::gossipsub;
 use rand::random;
-use tokio::sync::mpsc;
-use tokio::time::sleep;
-use crate::{block, network, input};
-use crate::blockchain::Blockchain;
-use crate::mempool::Mempool;
-use crate::output::Output;
+use tokio::sync::{broadcast, watch};
+use crate::{block, input};
+use crate::amount::Amount;
+use crate::hashes::{BlockHash, Txid};
+use crate::mempool::{Mempool, MempoolEvent};
+use crate::node::NodeHandle;
+use crate::params::ChainParams;
+use crate::payout::Payout;
+use crate::pow::{self, PowJob, PowSearch, Solution};
 use crate::transactions::Tx;
+use std::error::Error;
 use std::sync::Arc;
-use async_std::prelude::FutureExt;
-use num_format::Locale::ca;
-use block::Block;
+use std::time::Instant;
+use block::{Block, BlockTemplate};
 use input::Input;
 
+// how often the hashrate is printed while mining
+pub const HASHRATE_REPORT_SECS: u64 = 10;
+// nonces searched for each extra nonce, once they're used up the coinbase and timestamp change and the search starts over
+pub const NONCES_PER_EXTRA_NONCE: u64 = 1 << 32;
+
+// how a search over one header's nonce range ended
+enum SearchResult {
+    Solved(Solution),
+    Exhausted,
+    // the tip changed or the template was outbid
+    Stale,
+}
+
 #[derive(Clone)]
 
 
 pub struct Miner {
-    address: [u8;32],
-    consensus: Arc<Mutex<Block>>,
+    payout: Payout,
+    // number of threads hashing in parallel
+    threads: usize,
+    // the validating node whose tip is mined on, and whose mempool fills templates
+    node: NodeHandle,
 }
 impl Miner {
 
-    pub fn new(wallet_addr: [u8;32]) -> Miner {
-        let genesis_block = Block {index: 0, hash: [8;32], previous_hash: [0;32], transactions: Vec::new(),
-            time: 0, nonce: 420, target: 2u64.pow(64-24)};
-        Miner { address: wallet_addr, consensus: Arc::new(Mutex::new(genesis_block.clone()))}
+    pub fn new(payout: Payout, node: NodeHandle) -> Miner {
+        Miner { payout, threads: pow::default_threads(), node }
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
     }
 
+    // mines on the node's tip forever. found blocks go through the node's validation like any other block, and
+    // gossiping them to peers is left to the node
     pub async fn mine(&mut self) {
-        let mut swarm = network::GossipSwarm::new().unwrap();
-        let topic_consensus = gossipsub::IdentTopic::new("consensus-block");
-        swarm.subscribe(topic_consensus.clone()).unwrap();
-        swarm.publish(topic_consensus).unwrap();
-
-        let swarm_mutex = Arc::new(Mutex::new(swarm));
-        let consensus_mutex = Arc::clone(&self.consensus);
-
-        let send_consensus = {
-            let swarm_mutex = Arc::clone(&swarm_mutex);
-            let consensus_mutex = Arc::clone(&consensus_mutex);
-            let (mut tx, mut rx) = mpsc::channel(32);
-            tokio::spawn(async move {
-                loop {
-                    let consensus_block = {
-                        let consensus_lock = consensus_mutex.lock().await;
-                        consensus_lock.clone()
-                    };
-                    if let Err(e) = tx.send(consensus_block.clone()).await {
-                        eprintln!("Tx Error {e}");
-                    }
-                    else {
-                        let mut swarm_lock = swarm_mutex.lock().await;
-                        if let Err(pub_e) = swarm_lock.send_message(&mut rx).await {
-                            eprintln!("Publishing Error {pub_e}");
-                        }
-                        else {
-                            // if block is sent successfully, its index is updated
-                            println!("Sent Block {} successfully ", consensus_block.index);
-                        }
-                        sleep(Duration::from_secs(1)).await;
-                    }
+        // announces every new tip, so hashing on a stale parent can be abandoned
+        let mut tip_rx = self.node.subscribe_tip();
+        let mut pool_events = self.node.pool.lock().await.subscribe();
+        loop {
+            // the template below is built on the latest tip, so earlier tip changes are already accounted for
+            let tip = tip_rx.borrow_and_update().clone();
+            let Some(candidate_block) = Self::generate_candidate_block(tip, &self.payout, &self.node.params, self.threads,
+                                                                       &self.node.pool, &mut tip_rx, &mut pool_events).await else {
+                println!("Mining work is stale, building a new template");
+                continue;
+            };
+            let (index, hash) = (candidate_block.index, candidate_block.hash);
+            match self.node.submit_block(candidate_block.clone()).await {
+                Ok(()) => {
+                    println!("Mined block {index} {hash}");
+                    candidate_block.print();
                 }
-            })
-        };
+                Err(e) => eprintln!("Node rejected mined block {hash}: {e}"),
+            }
+        }
+    }
 
-        let handle_events = {
-            let mut swarm_mutex = Arc::clone(&swarm_mutex);
-            let mut consensus_mutex = Arc::clone(&consensus_mutex);
-            tokio::spawn(async move {
-                loop {
-                    let events = {
-                        let mut swarm_lock = swarm_mutex.lock().await;
-                        swarm_lock.handle_events().await
-                    };
-                    match events {
-                        Ok(Some(blk)) => {
-                            let mut consensus_block = {
-                                let consensus_lock = consensus_mutex.lock().await;
-                                consensus_lock.clone()
-                            };
-                            if blk.index > consensus_block.index {
-                                let mut consensus_lock = consensus_mutex.lock().await;
-                                consensus_lock.previous_hash = blk.previous_hash;
-                                consensus_lock.hash = blk.hash;
-                                consensus_lock.index += blk.index;
-
-                                println!("Received new consensus from node!");
-                                print!("Hash: ");
-                                blk.hash.iter().for_each(|hex| print!("{:02x}", hex));
-                                println!();
-                                blk.print();
-                            } else {
-                                println!("Received consensus block from node! ");
-                                print!("Hash: ");
-                                blk.hash.iter().for_each(|hex| print!("{:02x}", hex));
-                                println!();
-                            }
-                        }
-                        Ok(None) => {
-                            println!("No event received");
-                        }
-                        Err(e) => {
-                            eprintln!("Error handling event: {:?}", e);
-                        }
-                    }
-                }
-            })
-        };
-        let send_candidate = {
-            let consensus_mutex = Arc::clone(&consensus_mutex);
-            let swarm_mutex = Arc::clone(&swarm_mutex);
-            let address = self.address;
-            let (mut tx, mut rx) = mpsc::channel(32);
-            tokio::spawn(async move {
-                loop {
-                    let candidate_data = {
-                        // Acquire the lock briefly to clone the data
-                        let consensus_lock = consensus_mutex.lock().await;
-                        consensus_lock.clone() // Clone only the data needed for block generation
-                    };
-                    println!("Trying to find candidate block!");
-                    let candidate_block = Self::generate_candidate_block(candidate_data, address).await;
-                    candidate_block.print();
+    // mines blocks on the node's tip one after another without waiting for hashes, only possible with regtest's
+    // trivial target. the blocks take what they can from the mempool and go through the node's validation
+    pub async fn generate_blocks(node: &NodeHandle, blocks: u32, payout: &Payout) -> Result<Vec<BlockHash>, Box<dyn Error + Send + Sync>> {
+        if !node.params.is_regtest() {
+            return Err("blocks can only be generated on regtest".into());
+        }
+        let coinbase_size = Self::generate_coinbase(Amount::ZERO, payout, 0).get_size();
+        let mut hashes = vec![];
+        for _ in 0..blocks {
+            let template = {
+                let chain_lock = node.chain.lock().await;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                BlockTemplate::new(chain_lock.chain.last().unwrap(), &*node.pool.lock().await, &node.params, coinbase_size, now)?
+            };
+            let mut transactions = vec![Self::generate_coinbase(template.coinbase_value, payout, 0)];
+            transactions.extend(template.transactions);
+            let mut block = Block { index: template.index, hash: BlockHash::ZERO, previous_hash: template.previous_hash, time: template.time,
+                target: template.target, nonce: 0, merkle_root: Block::calc_merkle_root(&transactions),
+                witness_root: Block::calc_witness_root(&transactions), transactions };
+            let job = block.pow_job();
+            block.nonce = (0..).find(|nonce| pow::meets_target(&job.hash(*nonce), job.target)).ok_or("nonce space exhausted")?;
+            block.hash = job.hash(block.nonce);
+            let hash = block.hash;
+            node.submit_block(block).await?;
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
 
-                    // sends candidate block to network
-                    let mut swarm_lock = swarm_mutex.lock().await;
-                    if let Err(e) = tx.send(candidate_block.clone()).await {
-                        eprintln!("Tx Error {e}");
-                    }
-                    else {
-                        let mut consensus_lock = consensus_mutex.lock().await;
-                        if let Err(pub_e) = swarm_lock.send_message(&mut rx).await {
-                            eprintln!("Publishing Error {pub_e}");
-                        }
-                        else {
-                            // if block is sent successfully, its index is updated
-                            println!("Sent Block {} successfully ", candidate_block.index);
-                            consensus_lock.hash = candidate_block.hash;
-                            consensus_lock.previous_hash = candidate_block.previous_hash;
-                            consensus_lock.index = candidate_block.index;
-                        }
-                    }
+    // returns None if the tip changes, or better paying transactions arrive, before a valid hash is found. every
+    // header gets NONCES_PER_EXTRA_NONCE nonces, then the extra nonce in the coinbase is bumped, which changes the
+    // merkle root, the timestamp is brought up to date, and the search goes on over the new header
+    async fn generate_candidate_block(tip: Block, payout: &Payout, params: &ChainParams, threads: usize, pool: &Arc<Mutex<Mempool>>,
+                                      tip_rx: &mut watch::Receiver<Block>, pool_events: &mut broadcast::Receiver<MempoolEvent>) -> Option<Block> {
+        // the coinbase is the same size whatever it pays, so the space left for the pool is known before the fees are
+        let coinbase_size = Self::generate_coinbase(Amount::ZERO, payout, 0).get_size();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let pool_lock = pool.lock().await;
+        let template = BlockTemplate::new(&tip, &pool_lock, params, coinbase_size, now);
+        // the cheapest rate the template pays, a new transaction below it can only get in if there's room left
+        let lowest_fee_rate = template.iter().flat_map(|template| template.transactions.iter())
+            .filter_map(|tx| pool_lock.get(&tx.txid)).map(|entry| entry.fee_rate).min().unwrap_or(0);
+        drop(pool_lock);
+        let template = match template {
+            Ok(template) => template,
+            Err(e) => {
+                // nothing to mine until the tip or the mempool changes
+                eprintln!("Error building block template: {e}");
+                tokio::select! {
+                    _ = tip_rx.changed() => {}
+                    _ = pool_events.recv() => {}
                 }
-            })
+                return None;
+            }
         };
+        let max_size = block::MAX_BLOCK_SIZE - block::HEADER_BYTES - coinbase_size;
+        let space_left = max_size - template.transactions.iter().map(|tx| tx.get_size()).sum::<u32>();
+        let outbid = Self::wait_for_better_template(pool, pool_events, max_size, template.fees, space_left, lowest_fee_rate);
+        tokio::pin!(outbid);
+        let started = Instant::now();
+        println!("Mining block {} on {threads} thread(s), target {:016x}, {:.0} hashes expected", template.index, template.target, pow::expected_hashes(template.target));
 
+        for extra_nonce in 0.. {
+            let mut transactions = vec![];
+            transactions.push(Self::generate_coinbase(template.coinbase_value, payout, extra_nonce));
+            transactions.extend(template.transactions.iter().cloned());
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().max(template.time);
+            let job = PowJob { index: template.index, previous_hash: template.previous_hash, merkle_root: Block::calc_merkle_root(&transactions),
+                witness_root: Block::calc_witness_root(&transactions), time, target: template.target };
 
-
-       let _ =  tokio::join!(handle_events,send_consensus,send_candidate);
+            match Self::gen_valid_hash(job.clone(), threads, extra_nonce, started, tip_rx, &mut outbid).await {
+                SearchResult::Solved(solution) => {
+                    return Some(Block { index: job.index, hash: solution.hash, previous_hash: job.previous_hash, time: job.time, target: job.target,
+                        nonce: solution.nonce, merkle_root: job.merkle_root, witness_root: job.witness_root, transactions });
+                }
+                SearchResult::Exhausted => println!("Nonce range exhausted, rolling the extra nonce to {}", extra_nonce + 1),
+                SearchResult::Stale => return None,
+            }
+        }
+        None
     }
 
-    async fn generate_candidate_block(consensus: Block, address: [u8;32]) -> Block {
-        let (hash,nonce) = Self::gen_valid_hash(consensus.index+1, consensus.hash, consensus.target).await;
-        //let (mut transactions, fees) = pool.calc_valid_tx_pool_and_fees(&chain);
-        let mut transactions = vec![];
-        transactions.push(Self::generate_coinbase(0, address));
-
-        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let mut candidate = Block { index: consensus.index +1, hash, previous_hash: consensus.hash,
-            time, target: consensus.target, nonce, transactions };
-        candidate
-    }
-    fn generate_coinbase(fees: u64, address: [u8;32]) -> Tx {
-        const REWARD: u64 = 5000000;
+    // pays value, the subsidy plus fees, to payout. extra_nonce leads the coinbase data, the rest is random so
+    // miners paying the same address never share a txid
+    pub fn generate_coinbase(value: Amount, payout: &Payout, extra_nonce: u64) -> Tx {
         let mut inputs = vec![];
-        let mut outputs = vec![];
-        // inputs aren't important to coinbase Tx, however random signature given to prevent duplicate txid hash
         let mut signature: [u8; 64] = [0; 64];
         signature.iter_mut().for_each(|elm| *elm = random());
+        signature[..8].copy_from_slice(&extra_nonce.to_be_bytes());
 
 
-        let coinbase_input = Input { txid: [0; 32], signature,};
-        let coinbase_output = Output { amount: REWARD+fees, address};
+        let coinbase_input = Input { txid: Txid::ZERO, vout: 0, signature,};
+        let outputs = payout.outputs(value);
 
 
         inputs.push(coinbase_input);
-        outputs.push(coinbase_output);
         let txid = Tx::generate_txid(&inputs, &outputs);
         Tx { txid, inputs, outputs }
     }
 
-    async fn gen_valid_hash(index: u32, prev_hash: [u8;32], target: u64) -> ([u8;32],u64) {
-        let (mut hash, mut nonce) = Self::gen_hash_nonce(index, prev_hash).await;
-        while Miner::h2_u64(hash) > target {
-            (hash, nonce) = Self::gen_hash_nonce(index, prev_hash).await;
+    // resolves once the pool could fill a template paying more than fees. rebuilding the template is quadratic in the
+    // pool size, so it's only done for additions that could change it: ones that fit in the space_left or pay more
+    // than the template's lowest_fee_rate
+    async fn wait_for_better_template(pool: &Arc<Mutex<Mempool>>, pool_events: &mut broadcast::Receiver<MempoolEvent>, max_size: u32, fees: Amount,
+                                      space_left: u32, lowest_fee_rate: u64) {
+        loop {
+            let added = match pool_events.recv().await {
+                // removals can only lower what a template pays
+                Ok(MempoolEvent::Removed { .. }) => continue,
+                Ok(MempoolEvent::Added { txid }) => Some(txid),
+                // a lagged receiver missed some events, so the pool is checked whatever was added
+                Err(broadcast::error::RecvError::Lagged(_)) => None,
+                // no more events will come, the template can only go stale through a new tip
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            };
+            let pool_lock = pool.lock().await;
+            if let Some(txid) = added {
+                match pool_lock.get(&txid) {
+                    Some(entry) if entry.size <= space_left || entry.fee_rate > lowest_fee_rate => {}
+                    // already gone again, or it can't displace anything in the template
+                    _ => continue,
+                }
+            }
+            let (_, template_fees) = pool_lock.calc_valid_tx_pool_and_fees(max_size);
+            if template_fees > fees {
+                return;
+            }
         }
+    }
 
-        (hash, nonce)
+    // hashing runs on a pool of OS threads, each taking its own slice of the header's nonces in order, and the task
+    // only waits for the first solution to come back, reporting the hashrate and progress while it does. if the tip
+    // changes or the template is outbid first, the search is dropped, which stops the threads
+    async fn gen_valid_hash(job: PowJob, threads: usize, extra_nonce: u64, started: Instant, tip_rx: &mut watch::Receiver<Block>,
+                            outbid: &mut (impl std::future::Future<Output = ()> + Unpin)) -> SearchResult {
+        let (index, target) = (job.index, job.target);
+        let mut search = PowSearch::start_range(job, threads, 0..NONCES_PER_EXTRA_NONCE, target);
+        let window_started = Instant::now();
+        let mut report = tokio::time::interval(Duration::from_secs(HASHRATE_REPORT_SECS));
+        // the first tick completes straight away
+        report.tick().await;
+        loop {
+            tokio::select! {
+                solution = search.results.recv() => {
+                    // every thread finished its slice without a solution
+                    let Some(solution) = solution else { return SearchResult::Exhausted };
+                    println!("Found block {index} after {} with extra nonce {extra_nonce}", pow::fmt_duration(started.elapsed().as_secs_f64()));
+                    return SearchResult::Solved(solution);
+                }
+                _ = report.tick() => {
+                    let hashes = search.hashes();
+                    let rate = hashes as f64 / window_started.elapsed().as_secs_f64();
+                    println!("Hashrate {}, expected time to block {}, extra nonce {extra_nonce} {:.2}% searched", pow::fmt_hashrate(rate),
+                             pow::fmt_duration(pow::expected_hashes(target) / rate), hashes as f64 * 100.0 / NONCES_PER_EXTRA_NONCE as f64);
+                }
+                _ = tip_rx.changed() => return SearchResult::Stale,
+                _ = &mut *outbid => return SearchResult::Stale,
+            }
+        }
     }
+}
 
-    async fn gen_hash_nonce(index: u32, prev_hash: [u8;32]) -> ([u8;32],u64) {
-        let mut hasher = blake3::Hasher::new();
-        let nonce: u64 = random();
-        hasher.update(&index.to_be_bytes());
-        hasher.update(&prev_hash);
-        hasher.update(&nonce.to_be_bytes());
-        (*hasher.finalize().as_bytes(),nonce)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Node;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn generate_blocks_mines_pooled_transactions() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("generate", &key, 1).await.handle();
+        let coinbase = node.chain.lock().await.chain[1].transactions[0].clone();
+        let tx = testing::spend(&key, &coinbase, 0, testing::address(&testing::key(2)), Amount::from_base_units(1000));
+        testing::add_to_pool(&node, tx.clone()).await;
+
+        let hashes = Miner::generate_blocks(&node, 2, &Payout::single(testing::address(&key)).unwrap()).await.unwrap();
+        assert_eq!(hashes.len(), 2);
+        let chain_lock = node.chain.lock().await;
+        assert_eq!(chain_lock.get_height(), 3);
+        assert_eq!(chain_lock.get_current_hash(), hashes[1]);
+        assert_eq!(node.subscribe_tip().borrow().hash, hashes[1]);
+        assert!(chain_lock.chain[2].transactions.contains(&tx));
+        assert_eq!(node.pool.lock().await.len(), 0);
     }
 
-    fn h2_u64(hash: [u8; 32]) -> u64 {
-        let mut value: u64 = 0;
-        for i in 0..8 {
-            value |= (hash[i] as u64) << (8 * (7 - i)); // Shifting from the most significant byte to the least
-        }
-        value
+    #[tokio::test]
+    async fn generate_blocks_is_refused_on_mainnet() {
+        let node = Node::new(&ChainParams::mainnet(), testing::data_dir("generate-mainnet")).handle();
+        assert!(Miner::generate_blocks(&node, 1, &Payout::single(testing::address(&testing::key(1))).unwrap()).await.is_err());
     }
 }
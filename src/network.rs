@@ -15,6 +15,7 @@ use tokio::{io, io::AsyncBufReadExt, select};
 use tracing_subscriber::EnvFilter;
 use block::Block;
 use crate::block;
+use crate::hashes::BlockHash;
 use tokio::time;
 
 #[derive(NetworkBehaviour)]
@@ -26,6 +27,13 @@ pub struct GossipNet {
 pub struct GossipSwarm {
     swarm: Swarm<GossipNet>,
     publishing_topic: Option<gossipsub::IdentTopic>,
+    request_topic: Option<gossipsub::IdentTopic>,
+}
+
+// what peers gossip: blocks, and requests for blocks they are missing
+pub enum GossipMessage {
+    Block(Block),
+    BlockRequest(BlockHash),
 }
 
 impl GossipSwarm {
@@ -71,12 +79,12 @@ impl GossipSwarm {
             })?
             .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(10)))
             .build();
-        Ok (GossipSwarm { swarm, publishing_topic: None, })
+        Ok (GossipSwarm { swarm, publishing_topic: None, request_topic: None })
     }
 
-    pub fn subscribe(&mut self, topic: gossipsub::IdentTopic) -> Result<(), Box<dyn Error>> {
+    pub fn subscribe(&mut self, topic: gossipsub::IdentTopic, port: u16) -> Result<(), Box<dyn Error>> {
         // Listen on all interfaces and whatever port the OS assigns
-        self.swarm.listen_on("/ip4/0.0.0.0/tcp/42070".parse()?)?;
+        self.swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{port}").parse()?)?;
         self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?; // Propagate errors if subscription fails
         Ok(())
     }
@@ -86,7 +94,26 @@ impl GossipSwarm {
         Ok(())
     }
 
-    pub async fn handle_events(&mut self) -> Result<Option<Block>, Box<dyn Error + Send>> {
+    // requests are sent and received on topic, peers answer them by publishing the block
+    pub fn subscribe_requests(&mut self, topic: gossipsub::IdentTopic) -> Result<(), Box<dyn Error>> {
+        self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        self.request_topic = Some(topic);
+        Ok(())
+    }
+
+    pub fn request_block(&mut self, hash: &BlockHash) -> Result<(), Box<dyn Error>> {
+        let topic = self.request_topic.clone().ok_or("not subscribed to block requests")?;
+        self.swarm.behaviour_mut().gossipsub.publish(topic, serde_json::to_string(hash)?)?;
+        Ok(())
+    }
+
+    pub fn publish_block(&mut self, block: &Block) -> Result<(), Box<dyn Error>> {
+        let topic = self.publishing_topic.clone().ok_or("no topic to publish blocks on")?;
+        self.swarm.behaviour_mut().gossipsub.publish(topic, block.to_json()?)?;
+        Ok(())
+    }
+
+    pub async fn handle_events(&mut self) -> Result<Option<GossipMessage>, Box<dyn Error + Send>> {
         let timeout_duration = Duration::from_secs(5); // Set your desired timeout duration
         let timeout = time::timeout(timeout_duration, async {
             select! {
@@ -115,8 +142,17 @@ impl GossipSwarm {
                     message,
                 })) => {
                     println!("Peer that sent message: {}",peer_id);
+                    if self.request_topic.as_ref().is_some_and(|topic| topic.hash() == message.topic) {
+                        return match serde_json::from_slice(&message.data) {
+                            Ok(hash) => Ok(Some(GossipMessage::BlockRequest(hash))),
+                            Err(e) => {
+                                eprintln!("Invalid block request from {peer_id}: {e}");
+                                Ok(None)
+                            }
+                        };
+                    }
                     let message_block = Block::from_json(&String::from_utf8(message.data).unwrap()).unwrap();
-                    Ok(Some(message_block))
+                    Ok(Some(GossipMessage::Block(message_block)))
                 },
                 _ => Ok(None),
             }
@@ -1,41 +1,151 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use libp2p::gossipsub;
 use num_format::Locale::se;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, watch, Mutex};
 use tokio::time::sleep;
-use crate::block::Block;
+use crate::block::{Block, BlockError};
 use crate::blockchain::Blockchain;
 use crate::mempool::Mempool;
-use crate::network;
+use crate::network::{self, GossipMessage};
+use crate::pow;
+use crate::params::ChainParams;
+use crate::rpc;
+
+// how often the chain and mempool are written to the data directory
+pub const SAVE_INTERVAL_SECS: u64 = 60;
 
 pub struct Node {
-    chain: Arc<Mutex<Blockchain>>,
-    pool: Mempool,
-    utxos: HashMap<[u8;32],(u64,[u8;32])>
+    handle: NodeHandle,
+    data_dir: PathBuf,
+}
+
+// the node's shared state, cloned into everything that reads or extends the chain: the gossip tasks, the RPC
+// server and a local miner. locks are always taken chain first, then pool
+#[derive(Clone)]
+pub struct NodeHandle {
+    pub chain: Arc<Mutex<Blockchain>>,
+    pub pool: Arc<Mutex<Mempool>>,
+    pub params: ChainParams,
+    // the current tip, updated after every block connected
+    tip: Arc<watch::Sender<Block>>,
+}
+
+impl NodeHandle {
+    pub fn subscribe_tip(&self) -> watch::Receiver<Block> {
+        self.tip.subscribe()
+    }
+
+    // writes the chain and the mempool to data_dir, both under lock so the saved mempool matches the saved chain.
+    // returns the height and the number of transactions saved
+    pub async fn save(&self, data_dir: &Path) -> Result<(u32, usize), Box<dyn Error>> {
+        let chain_lock = self.chain.lock().await;
+        chain_lock.save(data_dir)?;
+        let pool_lock = self.pool.lock().await;
+        pool_lock.save(data_dir)?;
+        Ok((chain_lock.get_height(), pool_lock.len()))
+    }
+
+    // validates block and connects it. gossiped, submitted and locally mined blocks all come through here, so
+    // nothing reaches the chain, or a miner building on it, without being checked. a block on another branch is
+    // kept once its header checks out, and the chain switches to that branch as soon as it's the longer one. a
+    // block whose parent is unknown is kept as an orphan until the parent arrives, UnknownParent then names the
+    // missing ancestor to ask peers for
+    pub async fn submit_block(&self, block: Block) -> Result<(), BlockError> {
+        let mut chain_lock = self.chain.lock().await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let hash = block.hash;
+        self.connect_block(&mut chain_lock, block, now).await?;
+
+        // orphans waiting on the block, and on those orphans in turn, can follow it now
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for orphan in chain_lock.take_orphan_children(&parent) {
+                let orphan_hash = orphan.hash;
+                match self.connect_block(&mut chain_lock, orphan, now).await {
+                    Ok(()) => parents.push(orphan_hash),
+                    Err(e) => eprintln!("Orphan block {orphan_hash} rejected: {e}"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect_block(&self, chain: &mut Blockchain, block: Block, now: u64) -> Result<(), BlockError> {
+        let new_blocks = if block.previous_hash == chain.get_current_hash() {
+            vec![block]
+        } else {
+            if chain.get_block(&block.hash).is_some() {
+                return Err(BlockError::AlreadyKnown);
+            }
+            let Some(parent) = chain.get_block(&block.previous_hash).or(chain.get_side_block(&block.previous_hash)) else {
+                // without the parent only the proof of work can be checked, against the target every block shares
+                let missing = chain.get_missing_ancestor(&block.previous_hash);
+                if block.target == self.params.genesis_target && block.hash == block.pow_job().hash(block.nonce) && pow::meets_target(&block.hash, block.target) {
+                    chain.add_orphan_block(block);
+                }
+                return Err(BlockError::UnknownParent(missing));
+            };
+            block.check_header(parent, now)?;
+            let hash = block.hash;
+            chain.add_side_block(block);
+            let branch = chain.get_branch(&hash).unwrap_or_default();
+            if branch.last().is_none_or(|last| last.index <= chain.get_height()) {
+                return Ok(());
+            }
+            branch
+        };
+        let mut pool_lock = self.pool.lock().await;
+        Node::reorganize(chain, &mut pool_lock, &self.params, new_blocks, now)?;
+        self.tip.send_replace(chain.chain.last().unwrap().clone());
+        Ok(())
+    }
 }
 
 
 impl Node {
-    pub fn new() -> Node {
-        let genesis_block = Block {index: 0, hash: [8;32], previous_hash: [0;32], transactions: Vec::new(),
-            time: 0, nonce: 420, target: 2u64.pow(64-24)};
-        let chain_v = vec![genesis_block];
-        let initial_chain = Blockchain { chain: chain_v};
+    pub fn new(params: &ChainParams, data_dir: PathBuf) -> Node {
+        let initial_chain = match Blockchain::load(params, &data_dir) {
+            Ok(chain) => {
+                println!("Loaded {} blocks", chain.get_height());
+                chain
+            }
+            Err(e) => {
+                eprintln!("Error loading chain: {e}");
+                Blockchain::create_from_genesis(params.genesis_block())
+            }
+        };
+
+        // saved transactions are admitted again against the utxo set of the saved chain
+        let mut pool = Mempool::new();
+        match pool.load(&data_dir, &initial_chain.utxos) {
+            Ok(count) => println!("Loaded {count} transactions into the mempool"),
+            Err(e) => eprintln!("Error loading mempool: {e}"),
+        }
+
+        let (tip, _) = watch::channel(initial_chain.chain.last().unwrap().clone());
+        let handle = NodeHandle { chain: Arc::new(Mutex::new(initial_chain)), pool: Arc::new(Mutex::new(pool)), params: params.clone(), tip: Arc::new(tip) };
+        Node {handle, data_dir}
+    }
 
-        Node {chain: Arc::new(Mutex::new(initial_chain)), pool: Mempool::new(), utxos: HashMap::new()}
+    pub fn handle(&self) -> NodeHandle {
+        self.handle.clone()
     }
 
     pub async fn send_recv_consensus(&mut self) {
         let mut swarm = network::GossipSwarm::new().unwrap();
-        let topic_consensus = gossipsub::IdentTopic::new("consensus-block");
-        swarm.subscribe(topic_consensus.clone()).unwrap();
+        let network = self.handle.params.network;
+        let topic_consensus = gossipsub::IdentTopic::new(network.block_topic());
+        swarm.subscribe(topic_consensus.clone(), network.gossip_port()).unwrap();
         swarm.publish(topic_consensus).unwrap();
+        swarm.subscribe_requests(gossipsub::IdentTopic::new(network.block_request_topic())).unwrap();
 
         let (mut tx, mut rx) = mpsc::channel(32);
         let swarm_mutex = Arc::new(Mutex::new(swarm));
-        let chain_mutex = Arc::clone(&self.chain);
+        let chain_mutex = Arc::clone(&self.handle.chain);
 
 
         let send_message = {
@@ -65,7 +175,7 @@ impl Node {
 
         let handle_events = {
             let swarm_mutex = Arc::clone(&swarm_mutex);
-            let chain_mutex = Arc::clone(&chain_mutex);
+            let node = self.handle.clone();
 
             tokio::spawn(async move {
                 loop {
@@ -74,17 +184,37 @@ impl Node {
                         swarm_lock.handle_events().await
                     };
                     match events {
-                        Ok(Some(blk)) => {
-                            let mut chain_lock = chain_mutex.lock().await;
-                            if blk.index > chain_lock.chain.last().unwrap().index {
-                                chain_lock.add_block(blk.clone());
-                                println!("Received new block from peer");
-                                blk.print();
-                            } else {
+                        Ok(Some(GossipMessage::Block(blk))) => {
+                            let tip_hash = node.chain.lock().await.get_current_hash();
+                            if blk.hash == tip_hash {
                                 println!("Received consensus block from peer! ");
-                                print!("Hash: ");
-                                blk.hash.iter().for_each(|hex| print!("{:02x}", hex));
-                                println!();
+                                println!("Hash: {}", blk.hash);
+                            } else {
+                                match node.submit_block(blk.clone()).await {
+                                    // the missing blocks are asked for, they connect along with the orphans once they arrive
+                                    Err(BlockError::UnknownParent(missing)) => {
+                                        println!("Block {} from peer is an orphan, requesting {missing}", blk.hash);
+                                        if let Err(e) = swarm_mutex.lock().await.request_block(&missing) {
+                                            eprintln!("Error requesting block {missing}: {e}");
+                                        }
+                                    }
+                                    Err(e) => println!("Rejected block {} from peer: {e}", blk.hash),
+                                    Ok(()) => {
+                                        println!("Received new block from peer");
+                                        blk.print();
+                                    }
+                                }
+                            }
+                        }
+                        Ok(Some(GossipMessage::BlockRequest(hash))) => {
+                            let block = {
+                                let chain_lock = node.chain.lock().await;
+                                chain_lock.get_block(&hash).or(chain_lock.get_side_block(&hash)).cloned()
+                            };
+                            if let Some(block) = block {
+                                if let Err(e) = swarm_mutex.lock().await.publish_block(&block) {
+                                    eprintln!("Error answering request for block {hash}: {e}");
+                                }
                             }
                         }
                         Ok(None) => {
@@ -97,6 +227,290 @@ impl Node {
                 }
             })
         };
-        let _ = tokio::join!(handle_events, send_message);
+        let serve_rpc = {
+            let node = self.handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = rpc::serve(node).await {
+                    eprintln!("RPC server error: {e}");
+                }
+            })
+        };
+
+        // saved regularly as well as on shutdown, so a crash loses at most SAVE_INTERVAL_SECS of blocks and transactions
+        let save_state = {
+            let node = self.handle.clone();
+            let data_dir = self.data_dir.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(SAVE_INTERVAL_SECS));
+                loop {
+                    let shutdown = tokio::select! {
+                        _ = interval.tick() => false,
+                        signal = tokio::signal::ctrl_c() => signal.is_ok(),
+                    };
+                    match node.save(&data_dir).await {
+                        Ok((blocks, transactions)) if shutdown => println!("Saved {blocks} blocks and {transactions} mempool transactions"),
+                        Ok(_) => {}
+                        Err(e) => eprintln!("Error saving node state: {e}"),
+                    }
+                    if shutdown {
+                        std::process::exit(0);
+                    }
+                }
+            })
+        };
+        let _ = tokio::join!(handle_events, send_message, serve_rpc, save_state);
     }
-}
\ No newline at end of file
+
+    // connects new_blocks, the first of which builds on a block already in the chain. if that block isn't the tip,
+    // everything above it is disconnected first, as long as the new branch ends up longer. each new block is
+    // validated against the chain as it stands once its parent is connected, and if one fails the old branch is
+    // put back. the mempool drops transactions the new blocks confirm or conflict with, and takes back those only
+    // the old branch had confirmed
+    fn reorganize(chain: &mut Blockchain, pool: &mut Mempool, params: &ChainParams, new_blocks: Vec<Block>, now: u64) -> Result<(), BlockError> {
+        let Some(first) = new_blocks.first() else { return Ok(()) };
+        let fork_height = chain.get_block(&first.previous_hash).map(|block| block.index).ok_or(BlockError::UnknownParent(first.previous_hash))?;
+        if new_blocks.last().unwrap().index <= chain.get_height() {
+            return Ok(());
+        }
+
+        let mut disconnected = vec![];
+        while chain.get_height() > fork_height {
+            match chain.disconnect_tip() {
+                Some(block) => disconnected.push(block),
+                None => break,
+            }
+        }
+        disconnected.reverse();
+
+        for (connected, block) in new_blocks.iter().enumerate() {
+            if let Err(e) = block.validate(chain, params, now) {
+                // nothing building on an invalid block is worth keeping either, without it their branch is cut off
+                chain.remove_side_block(&block.hash);
+                for _ in 0..connected {
+                    chain.disconnect_tip();
+                }
+                disconnected.into_iter().for_each(|block| chain.add_block(block));
+                return Err(e);
+            }
+            chain.add_block(block.clone());
+        }
+
+        let mut confirmed = HashSet::new();
+        for block in new_blocks.iter() {
+            block.transactions.iter().for_each(|tx| { confirmed.insert(tx.txid); });
+            pool.remove_for_block(block, &chain.utxos);
+        }
+        pool.readd_for_disconnect(&disconnected, &confirmed, &chain.utxos);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::blockchain::{CHAIN_FILE, MAX_ORPHAN_BLOCKS};
+    use crate::hashes::Address;
+    use crate::miner::Miner;
+    use crate::output::Output;
+    use crate::payout::Payout;
+    use crate::testing;
+    use crate::transactions::{Tx, TxError};
+
+    #[tokio::test]
+    async fn switches_to_a_longer_branch_and_back() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("reorg", &key, 2).await.handle();
+        let to = testing::address(&key);
+        let subsidy = node.params.block_subsidy(2);
+        let (first, old_second) = {
+            let chain_lock = node.chain.lock().await;
+            (chain_lock.chain[1].clone(), chain_lock.chain[2].clone())
+        };
+
+        // a branch as long as the chain is only kept
+        let second = testing::block_on(&first, subsidy, to, vec![]);
+        node.submit_block(second.clone()).await.unwrap();
+        assert_eq!(node.chain.lock().await.get_current_hash(), old_second.hash);
+        let third = testing::block_on(&second, subsidy, to, vec![]);
+        node.submit_block(third.clone()).await.unwrap();
+        {
+            let chain_lock = node.chain.lock().await;
+            assert_eq!(chain_lock.get_height(), 3);
+            assert_eq!(chain_lock.chain[2].hash, second.hash);
+            assert!(!chain_lock.utxos.contains_key(&old_second.transactions[0].inputs[0].outpoint()));
+            assert!(chain_lock.utxos.keys().all(|outpoint| outpoint.txid != old_second.transactions[0].txid));
+        }
+        assert_eq!(node.subscribe_tip().borrow().hash, third.hash);
+
+        // the disconnected block is still around, so its branch can take over again
+        let old_third = testing::block_on(&old_second, subsidy, to, vec![]);
+        let old_fourth = testing::block_on(&old_third, subsidy, to, vec![]);
+        node.submit_block(old_third).await.unwrap();
+        node.submit_block(old_fourth.clone()).await.unwrap();
+        let chain_lock = node.chain.lock().await;
+        assert_eq!(chain_lock.get_current_hash(), old_fourth.hash);
+        assert_eq!(chain_lock.chain[2].hash, old_second.hash);
+        assert_eq!(chain_lock.utxos.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn invalid_branch_leaves_the_chain_alone() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("bad-reorg", &key, 1).await.handle();
+        let to = testing::address(&key);
+        let (genesis, tip) = {
+            let chain_lock = node.chain.lock().await;
+            (chain_lock.chain[0].clone(), chain_lock.chain[1].clone())
+        };
+
+        let first = testing::block_on(&genesis, node.params.block_subsidy(1), to, vec![]);
+        node.submit_block(first.clone()).await.unwrap();
+        // the coinbase claims twice the subsidy, which only shows once the branch is connected
+        let greedy = testing::block_on(&first, node.params.block_subsidy(2).checked_mul(2).unwrap(), to, vec![]);
+        assert!(matches!(node.submit_block(greedy.clone()).await, Err(BlockError::CoinbaseTooLarge { .. })));
+        {
+            let chain_lock = node.chain.lock().await;
+            assert_eq!(chain_lock.get_current_hash(), tip.hash);
+            assert_eq!(chain_lock.utxos.len(), 1);
+            assert!(chain_lock.utxos.keys().all(|outpoint| outpoint.txid == tip.transactions[0].txid));
+        }
+        let after = testing::block_on(&greedy, node.params.block_subsidy(3), to, vec![]);
+        assert!(matches!(node.submit_block(after).await, Err(BlockError::UnknownParent(_))));
+        assert!(matches!(node.submit_block(tip).await, Err(BlockError::AlreadyKnown)));
+    }
+
+    #[tokio::test]
+    async fn reorganizing_moves_transactions_between_chain_and_mempool() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("reorg-mempool", &key, 2).await.handle();
+        let to = testing::address(&testing::key(2));
+        let fee = Amount::from_base_units(1000);
+        let (first_coinbase, second) = {
+            let chain_lock = node.chain.lock().await;
+            (chain_lock.chain[1].transactions[0].clone(), chain_lock.chain[2].clone())
+        };
+        let second_coinbase = second.transactions[0].clone();
+
+        // confirmed only on the branch that's about to lose
+        let confirmed = testing::spend(&key, &first_coinbase, 0, to, fee);
+        testing::add_to_pool(&node, confirmed.clone()).await;
+        Miner::generate_blocks(&node, 1, &Payout::single(to).unwrap()).await.unwrap();
+        let third = node.chain.lock().await.chain[3].clone();
+        // pooled, but spends the same coin as a transaction on the winning branch
+        let conflicted = testing::spend(&key, &second_coinbase, 0, to, fee);
+        testing::add_to_pool(&node, conflicted.clone()).await;
+
+        let winner = testing::spend(&key, &second_coinbase, 0, testing::address(&testing::key(3)), fee);
+        let subsidy = node.params.block_subsidy(3);
+        let new_third = testing::block_on(&second, subsidy, to, vec![winner.clone()]);
+        let new_fourth = testing::block_on(&new_third, subsidy, to, vec![]);
+        node.submit_block(new_third).await.unwrap();
+        node.submit_block(new_fourth).await.unwrap();
+        {
+            let pool_lock = node.pool.lock().await;
+            assert!(pool_lock.contains(&confirmed.txid));
+            assert!(!pool_lock.contains(&conflicted.txid));
+            assert!(!pool_lock.contains(&winner.txid));
+            assert_eq!(pool_lock.len(), 1);
+        }
+        // spends the winner's output, which only exists while its branch is the chain
+        let winner_child = testing::spend(&testing::key(3), &winner, 0, to, fee);
+        testing::add_to_pool(&node, winner_child.clone()).await;
+
+        // and back again, the old branch confirms its transaction once more along with one double spending the
+        // winner, so neither the winner nor its pooled child can be mined any more
+        let old_fourth = testing::block_on(&third, subsidy, to, vec![conflicted.clone()]);
+        let old_fifth = testing::block_on(&old_fourth, subsidy, to, vec![]);
+        node.submit_block(old_fourth).await.unwrap();
+        node.submit_block(old_fifth.clone()).await.unwrap();
+        assert_eq!(node.chain.lock().await.get_current_hash(), old_fifth.hash);
+        {
+            let pool_lock = node.pool.lock().await;
+            assert!(!pool_lock.contains(&confirmed.txid));
+            assert!(!pool_lock.contains(&winner.txid));
+            assert!(!pool_lock.contains(&winner_child.txid));
+            assert_eq!(pool_lock.len(), 0);
+        }
+        Miner::generate_blocks(&node, 1, &Payout::single(to).unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn block_spending_an_output_twice_in_one_transaction_is_rejected() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("double-input", &key, 1).await.handle();
+        let tip = node.chain.lock().await.chain[1].clone();
+        let coinbase = tip.transactions[0].clone();
+
+        // both inputs spend the same output, and one signature covers both, so only the duplicate check stops it
+        let twice = Output { amount: coinbase.outputs[0].amount.checked_mul(2).unwrap(), address: testing::address(&key) };
+        let mut tx = testing::pay(&key, &coinbase, 0, vec![twice]);
+        tx.inputs.push(tx.inputs[0]);
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs);
+        let block = testing::block_on(&tip, node.params.block_subsidy(2), testing::address(&key), vec![tx]);
+        assert!(matches!(node.submit_block(block).await, Err(BlockError::InvalidTx(TxError::DuplicateInput(_)))));
+        assert_eq!(node.chain.lock().await.get_current_hash(), tip.hash);
+    }
+
+    // count blocks building on parent, each paying its whole coinbase to to
+    fn blocks_on(parent: &Block, count: u32, params: &ChainParams, to: Address) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for _ in 0..count {
+            let parent = blocks.last().unwrap_or(parent);
+            blocks.push(testing::block_on(parent, params.block_subsidy(parent.index + 1), to, vec![]));
+        }
+        blocks
+    }
+
+    #[tokio::test]
+    async fn orphan_blocks_connect_once_their_parent_arrives() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("orphans", &key, 1).await.handle();
+        let tip = node.chain.lock().await.chain[1].clone();
+        let blocks = blocks_on(&tip, 3, &node.params, testing::address(&key));
+
+        // each orphan names the first block still missing below it, which is what peers get asked for
+        assert!(matches!(node.submit_block(blocks[2].clone()).await, Err(BlockError::UnknownParent(missing)) if missing == blocks[1].hash));
+        assert!(matches!(node.submit_block(blocks[1].clone()).await, Err(BlockError::UnknownParent(missing)) if missing == blocks[0].hash));
+        assert_eq!(node.chain.lock().await.get_height(), 1);
+        node.submit_block(blocks[0].clone()).await.unwrap();
+        assert_eq!(node.chain.lock().await.get_current_hash(), blocks[2].hash);
+        assert_eq!(node.subscribe_tip().borrow().hash, blocks[2].hash);
+    }
+
+    #[tokio::test]
+    async fn orphan_blocks_furthest_from_the_chain_are_dropped_first() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("orphan-limit", &key, 1).await.handle();
+        let tip = node.chain.lock().await.chain[1].clone();
+        let blocks = blocks_on(&tip, MAX_ORPHAN_BLOCKS as u32 + 2, &node.params, testing::address(&key));
+
+        // arriving newest first, as when walking back from a peer's tip
+        for block in blocks[1..].iter().rev() {
+            assert!(matches!(node.submit_block(block.clone()).await, Err(BlockError::UnknownParent(_))));
+        }
+        // everything but the newest connects, it was dropped to make room for the ones below it
+        node.submit_block(blocks[0].clone()).await.unwrap();
+        assert_eq!(node.chain.lock().await.get_height(), 1 + MAX_ORPHAN_BLOCKS as u32 + 1);
+        node.submit_block(blocks.last().unwrap().clone()).await.unwrap();
+        assert_eq!(node.chain.lock().await.get_current_hash(), blocks.last().unwrap().hash);
+    }
+
+    #[tokio::test]
+    async fn saved_chain_and_mempool_are_loaded_back() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("persist", &key, 2).await;
+        let handle = node.handle();
+        let coinbase = handle.chain.lock().await.chain[1].transactions[0].clone();
+        let tx = testing::spend(&key, &coinbase, 0, testing::address(&testing::key(2)), Amount::from_base_units(1000));
+        testing::add_to_pool(&handle, tx.clone()).await;
+        assert_eq!(handle.save(&node.data_dir).await.unwrap(), (2, 1));
+        assert!(!node.data_dir.join(CHAIN_FILE).with_extension("tmp").exists());
+
+        // the mempool only loads against the saved chain, its transaction spends a coin that genesis doesn't have
+        let reloaded = Node::new(&ChainParams::regtest(), node.data_dir.clone()).handle();
+        assert_eq!(reloaded.chain.lock().await.get_current_hash(), handle.chain.lock().await.get_current_hash());
+        assert_eq!(reloaded.subscribe_tip().borrow().index, 2);
+        assert!(reloaded.pool.lock().await.contains(&tx.txid));
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::{HashMap, HashSet};
+
+use libp2p::PeerId;
+
+use crate::hashes::Txid;
+use crate::transactions::Tx;
+
+pub const MAX_ORPHANS: usize = 100;
+pub const MAX_ORPHANS_PER_PEER: usize = 10;
+// orphans whose parents haven't shown up after this many seconds are dropped
+pub const ORPHAN_EXPIRY_SECS: u64 = 20 * 60;
+
+pub struct OrphanEntry {
+    pub tx: Tx,
+    // peer that relayed the orphan, None for locally submitted transactions
+    pub peer: Option<PeerId>,
+    pub time: u64,
+    missing_parents: Vec<Txid>,
+}
+
+// transactions that arrived before their parents, held until the parents are admitted to the mempool
+pub struct OrphanPool {
+    orphans: HashMap<Txid, OrphanEntry>,
+    // missing parent txid, and the orphans waiting for it
+    by_parent: HashMap<Txid, HashSet<Txid>>,
+}
+
+impl OrphanPool {
+    pub fn new() -> OrphanPool {
+        OrphanPool { orphans: HashMap::new(), by_parent: HashMap::new() }
+    }
+
+    pub fn add(&mut self, tx: Tx, missing_parents: Vec<Txid>, peer: Option<PeerId>, time: u64) -> bool {
+        if self.orphans.contains_key(&tx.txid) {
+            return false;
+        }
+        // a single peer can't fill the orphan pool on its own
+        if let Some(peer) = peer {
+            if self.orphans.values().filter(|orphan| orphan.peer == Some(peer)).count() >= MAX_ORPHANS_PER_PEER {
+                return false;
+            }
+        }
+        // when full, the oldest orphan makes room
+        if self.orphans.len() >= MAX_ORPHANS {
+            if let Some(oldest) = self.orphans.values().min_by_key(|orphan| orphan.time).map(|orphan| orphan.tx.txid) {
+                self.remove(&oldest);
+            }
+        }
+
+        missing_parents.iter().for_each(|parent| { self.by_parent.entry(*parent).or_default().insert(tx.txid); });
+        self.orphans.insert(tx.txid, OrphanEntry { tx, peer, time, missing_parents });
+        true
+    }
+
+    pub fn remove(&mut self, txid: &Txid) -> Option<OrphanEntry> {
+        let orphan = self.orphans.remove(txid)?;
+        for parent in orphan.missing_parents.iter() {
+            if let Some(waiting) = self.by_parent.get_mut(parent) {
+                waiting.remove(txid);
+                if waiting.is_empty() {
+                    self.by_parent.remove(parent);
+                }
+            }
+        }
+        Some(orphan)
+    }
+
+    // removes and returns the orphans waiting on parent, so they can be tried again
+    pub fn take_children(&mut self, parent: &Txid) -> Vec<OrphanEntry> {
+        let waiting = self.by_parent.remove(parent).unwrap_or_default();
+        waiting.iter().filter_map(|txid| self.remove(txid)).collect()
+    }
+
+    // drops orphans older than ORPHAN_EXPIRY_SECS and returns how many were dropped
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expired: Vec<Txid> = self.orphans.values()
+            .filter(|orphan| orphan.time + ORPHAN_EXPIRY_SECS < now)
+            .map(|orphan| orphan.tx.txid).collect();
+        expired.iter().for_each(|txid| { self.remove(txid); });
+        expired.len()
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.orphans.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize { self.orphans.len() }
+}
@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
+use crate::amount::Amount;
+use crate::hashes::Address;
 
 #[derive(Clone, Hash, Serialize, Deserialize)]
 pub struct Output {
-    pub amount: u64,
-    pub address: [u8;32],
+    pub amount: Amount,
+    pub address: Address,
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use crate::amount::{Amount, MAX_MONEY};
+use crate::block::Block;
+use crate::hashes::{BlockHash, MerkleRoot};
+
+// which chain a node is on, each has its own ports and gossip topic so a regtest node never talks to main ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Regtest,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Main => "main",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    pub fn gossip_port(&self) -> u16 {
+        match self {
+            Network::Main => 42070,
+            Network::Regtest => 43070,
+        }
+    }
+
+    pub fn rpc_port(&self) -> u16 {
+        match self {
+            Network::Main => 42071,
+            Network::Regtest => 43071,
+        }
+    }
+
+    pub fn stratum_port(&self) -> u16 {
+        match self {
+            Network::Main => 42072,
+            Network::Regtest => 43072,
+        }
+    }
+
+    pub fn block_topic(&self) -> String {
+        match self {
+            Network::Main => "consensus-block".to_string(),
+            network => format!("{}-consensus-block", network.name()),
+        }
+    }
+
+    // nodes missing a block ask their peers for it by hash on a topic of its own
+    pub fn block_request_topic(&self) -> String {
+        format!("{}-request", self.block_topic())
+    }
+}
+
+#[derive(Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    pub initial_subsidy: Amount,
+    // number of blocks between each halving of the subsidy
+    pub halving_interval: u32,
+    pub genesis_target: u64,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> ChainParams {
+        ChainParams { network: Network::Main, initial_subsidy: Amount::from_coins(5), halving_interval: 210000, genesis_target: 2u64.pow(64-24) }
+    }
+
+    // local testing chain, any hash meets the target so blocks can be made on demand
+    pub fn regtest() -> ChainParams {
+        ChainParams { network: Network::Regtest, initial_subsidy: Amount::from_coins(5), halving_interval: 150, genesis_target: u64::MAX }
+    }
+
+    pub fn is_regtest(&self) -> bool {
+        self.network == Network::Regtest
+    }
+
+    pub fn genesis_block(&self) -> Block {
+        Block {index: 0, hash: BlockHash::from_bytes([8;32]), previous_hash: BlockHash::ZERO, transactions: Vec::new(),
+            time: 0, nonce: 420, target: self.genesis_target, merkle_root: MerkleRoot::ZERO, witness_root: MerkleRoot::ZERO}
+    }
+
+    // the genesis block pays nothing, after that the subsidy halves every halving_interval blocks
+    pub fn block_subsidy(&self, height: u32) -> Amount {
+        if height == 0 {
+            return Amount::ZERO;
+        }
+        let halvings = (height - 1) / self.halving_interval;
+        if halvings >= 64 {
+            return Amount::ZERO;
+        }
+        Amount::from_base_units(self.initial_subsidy.to_base_units() >> halvings)
+    }
+
+    // total amount created by coinbase subsidies in blocks 0 through height, fees only move existing coins
+    pub fn issued_supply(&self, height: u32) -> Amount {
+        let mut supply = Amount::ZERO;
+        let mut era_start = 1;
+        while era_start <= height {
+            let subsidy = self.block_subsidy(era_start);
+            if subsidy == Amount::ZERO {
+                break;
+            }
+            let era_end = era_start.saturating_add(self.halving_interval - 1).min(height);
+            let era_blocks = (era_end - era_start + 1) as u64;
+            supply = subsidy.checked_mul(era_blocks).and_then(|issued| supply.checked_add(issued)).unwrap_or(MAX_MONEY);
+            match era_end.checked_add(1) {
+                Some(next) => era_start = next,
+                None => break,
+            }
+        }
+        supply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_supply_follows_the_halvings() {
+        let params = ChainParams::regtest();
+        let subsidy = params.initial_subsidy.to_base_units();
+        assert_eq!(params.issued_supply(0), Amount::ZERO);
+        assert_eq!(params.issued_supply(1).to_base_units(), subsidy);
+        assert_eq!(params.issued_supply(150).to_base_units(), 150 * subsidy);
+        assert_eq!(params.issued_supply(152).to_base_units(), 150 * subsidy + subsidy);
+        // once the subsidy reaches zero nothing more is issued
+        assert_eq!(params.issued_supply(u32::MAX), params.issued_supply(150 * 64));
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::amount::Amount;
+use crate::hashes::{self, Address, HexError};
+use crate::output::Output;
+
+// one output of a coinbase, paying percent of the block reward to address
+#[derive(Clone, Copy, Debug)]
+pub struct PayoutShare {
+    pub address: Address,
+    pub percent: u8,
+}
+
+// where mined coins go, a single address or a split across several. only built through the constructors below,
+// which all validate, so a Payout is always one whose outputs can be spent
+#[derive(Clone, Debug)]
+pub struct Payout {
+    shares: Vec<PayoutShare>,
+}
+
+#[derive(Debug)]
+pub enum PayoutError {
+    Empty,
+    InvalidAddress(Address),
+    InvalidHex(HexError),
+    InvalidPercent(String),
+    ZeroPercent(Address),
+    DuplicateAddress(Address),
+    // the percentages have to add up to exactly 100
+    BadTotal(u32),
+}
+
+impl fmt::Display for PayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayoutError::Empty => write!(f, "no payout address"),
+            PayoutError::InvalidAddress(address) => write!(f, "{address} is not a valid public key"),
+            PayoutError::InvalidHex(e) => write!(f, "{e}"),
+            PayoutError::InvalidPercent(percent) => write!(f, "invalid percentage {percent:?}"),
+            PayoutError::ZeroPercent(address) => write!(f, "{address} is paid 0%"),
+            PayoutError::DuplicateAddress(address) => write!(f, "{address} is listed more than once"),
+            PayoutError::BadTotal(total) => write!(f, "percentages add up to {total}%, not 100%"),
+        }
+    }
+}
+
+impl Error for PayoutError {}
+
+impl Payout {
+    pub fn new(shares: Vec<PayoutShare>) -> Result<Payout, PayoutError> {
+        Self::validate(&shares)?;
+        Ok(Payout { shares })
+    }
+
+    pub fn single(address: Address) -> Result<Payout, PayoutError> {
+        Self::new(vec![PayoutShare { address, percent: 100 }])
+    }
+
+    // pays the public key of the hex encoded secret key stored in a wallet file
+    pub fn from_wallet(path: &Path) -> Result<Payout, Box<dyn Error>> {
+        let secret = hashes::from_hex_32(fs::read_to_string(path)?.trim())?;
+        let address = Address::from_bytes(SigningKey::from_bytes(&secret).verifying_key().to_bytes());
+        Ok(Payout::single(address)?)
+    }
+
+    // every output has to be spendable, so each address must be a public key, and together they take the whole reward
+    fn validate(shares: &[PayoutShare]) -> Result<(), PayoutError> {
+        if shares.is_empty() {
+            return Err(PayoutError::Empty);
+        }
+        let mut seen = HashSet::new();
+        for share in shares.iter() {
+            if VerifyingKey::from_bytes(share.address.as_bytes()).is_err() {
+                return Err(PayoutError::InvalidAddress(share.address));
+            }
+            if share.percent == 0 {
+                return Err(PayoutError::ZeroPercent(share.address));
+            }
+            if !seen.insert(share.address) {
+                return Err(PayoutError::DuplicateAddress(share.address));
+            }
+        }
+        let total: u32 = shares.iter().map(|share| share.percent as u32).sum();
+        if total != 100 {
+            return Err(PayoutError::BadTotal(total));
+        }
+        Ok(())
+    }
+
+    // splits amount across the shares, the first share also gets whatever rounding leaves over
+    pub fn outputs(&self, amount: Amount) -> Vec<Output> {
+        let mut outputs: Vec<Output> = self.shares.iter().map(|share| {
+            let part = amount.to_base_units() as u128 * share.percent as u128 / 100;
+            Output { amount: Amount::from_base_units(part as u64), address: share.address }
+        }).collect();
+        let paid = Amount::checked_sum(outputs.iter().map(|output| output.amount)).unwrap_or(amount);
+        if let (Some(first), Some(rest)) = (outputs.first_mut(), amount.checked_sub(paid)) {
+            first.amount = first.amount.checked_add(rest).unwrap_or(first.amount);
+        }
+        outputs
+    }
+}
+
+// "ADDRESS" pays everything to one address, "ADDRESS:60,ADDRESS:40" splits it
+impl FromStr for Payout {
+    type Err = PayoutError;
+
+    fn from_str(s: &str) -> Result<Payout, PayoutError> {
+        let mut shares = vec![];
+        for part in s.split(',').map(|part| part.trim()).filter(|part| !part.is_empty()) {
+            let (address, percent) = match part.split_once(':') {
+                Some((address, percent)) => (address, percent.parse().map_err(|_| PayoutError::InvalidPercent(percent.to_string()))?),
+                None => (part, 100),
+            };
+            shares.push(PayoutShare { address: address.parse().map_err(PayoutError::InvalidHex)?, percent });
+        }
+        Payout::new(shares)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn addresses() -> [Address; 3] {
+        [1, 2, 3].map(|seed| testing::address(&testing::key(seed)))
+    }
+
+    fn amounts(payout: &Payout, amount: u64) -> Vec<u64> {
+        payout.outputs(Amount::from_base_units(amount)).iter().map(|output| output.amount.to_base_units()).collect()
+    }
+
+    #[test]
+    fn splits_by_percentage() {
+        let [a, b, c] = addresses();
+        let payout: Payout = format!("{a}:33,{b}:33,{c}:34").parse().unwrap();
+        assert_eq!(amounts(&payout, 1000), vec![330, 330, 340]);
+        let outputs = payout.outputs(Amount::from_base_units(1000));
+        assert_eq!(outputs.iter().map(|output| output.address).collect::<Vec<_>>(), vec![a, b, c]);
+        // everything goes to a lone address
+        assert_eq!(amounts(&a.to_string().parse().unwrap(), 1000), vec![1000]);
+    }
+
+    #[test]
+    fn rounding_dust_goes_to_the_first_share() {
+        let [a, b, c] = addresses();
+        let payout: Payout = format!("{a}:33,{b}:33,{c}:34").parse().unwrap();
+        assert_eq!(amounts(&payout, 101), vec![34, 33, 34]);
+        let payout: Payout = format!("{a}:60,{b}:40").parse().unwrap();
+        assert_eq!(amounts(&payout, 7), vec![5, 2]);
+        assert_eq!(amounts(&payout, 0), vec![0, 0]);
+    }
+
+    #[test]
+    fn percentages_must_add_up_to_100() {
+        let [a, b, _] = addresses();
+        assert!(matches!(format!("{a}:50,{b}:40").parse::<Payout>(), Err(PayoutError::BadTotal(90))));
+        assert!(matches!(format!("{a}:60,{b}:60").parse::<Payout>(), Err(PayoutError::BadTotal(120))));
+        assert!(matches!(format!("{a}:100,{b}:0").parse::<Payout>(), Err(PayoutError::ZeroPercent(address)) if address == b));
+    }
+
+    #[test]
+    fn rejects_bad_percentages_and_addresses() {
+        let [a, b, _] = addresses();
+        assert!(matches!(format!("{a}:half").parse::<Payout>(), Err(PayoutError::InvalidPercent(percent)) if percent == "half"));
+        assert!(matches!(format!("{a}:300").parse::<Payout>(), Err(PayoutError::InvalidPercent(_))));
+        assert!(matches!(format!("{a}:-50,{b}:150").parse::<Payout>(), Err(PayoutError::InvalidPercent(_))));
+        assert!(matches!(format!("{a}:50,{a}:50").parse::<Payout>(), Err(PayoutError::DuplicateAddress(address)) if address == a));
+        assert!(matches!("".parse::<Payout>(), Err(PayoutError::Empty)));
+        assert!(matches!("zz".parse::<Payout>(), Err(PayoutError::InvalidHex(_))));
+    }
+}
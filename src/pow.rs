@@ -0,0 +1,171 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::hashes::{BlockHash, MerkleRoot};
+
+// header fields the proof of work commits to, everything but the nonce
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PowJob {
+    pub index: u32,
+    pub previous_hash: BlockHash,
+    pub merkle_root: MerkleRoot,
+    pub witness_root: MerkleRoot,
+    pub time: u64,
+    pub target: u64,
+}
+
+impl PowJob {
+    pub fn hash(&self, nonce: u64) -> BlockHash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.index.to_be_bytes());
+        hasher.update(self.previous_hash.as_bytes());
+        hasher.update(self.merkle_root.as_bytes());
+        hasher.update(self.witness_root.as_bytes());
+        hasher.update(&self.time.to_be_bytes());
+        hasher.update(&nonce.to_be_bytes());
+        BlockHash::from_bytes(*hasher.finalize().as_bytes())
+    }
+}
+
+pub fn meets_target(hash: &BlockHash, target: u64) -> bool {
+    h2_u64(hash) <= target
+}
+
+// first 8 bytes of the hash, big endian
+pub fn h2_u64(hash: &BlockHash) -> u64 {
+    let mut value: u64 = 0;
+    for i in 0..8 {
+        value |= (hash.as_bytes()[i] as u64) << (8 * (7 - i)); // Shifting from the most significant byte to the least
+    }
+    value
+}
+
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1)
+}
+
+// average number of hashes needed to find one at or below target
+pub fn expected_hashes(target: u64) -> f64 {
+    2f64.powi(64) / (target as f64 + 1.0)
+}
+
+// e.g. 1234567.0 is "1.23 MH/s"
+pub fn fmt_hashrate(hashes_per_sec: f64) -> String {
+    let units = ["H/s", "kH/s", "MH/s", "GH/s", "TH/s"];
+    let mut rate = hashes_per_sec;
+    let mut unit = 0;
+    while rate >= 1000.0 && unit < units.len() - 1 {
+        rate /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", rate, units[unit])
+}
+
+// e.g. 5400.0 is "1h 30m 0s"
+pub fn fmt_duration(secs: f64) -> String {
+    if !secs.is_finite() {
+        return "never".to_string();
+    }
+    let secs = secs.round() as u64;
+    match (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60) {
+        (0, 0, 0, s) => format!("{s}s"),
+        (0, 0, m, s) => format!("{m}m {s}s"),
+        (0, h, m, s) => format!("{h}h {m}m {s}s"),
+        (d, h, m, _) => format!("{d}d {h}h {m}m"),
+    }
+}
+
+// hashes per second on the given number of threads, measured over duration
+pub async fn benchmark(threads: usize, duration: Duration) -> f64 {
+    // a zero target is never met in practice, so the search runs for the whole duration
+    let job = PowJob { index: 0, previous_hash: BlockHash::ZERO, merkle_root: MerkleRoot::ZERO, witness_root: MerkleRoot::ZERO, time: 0, target: 0 };
+    let search = PowSearch::start(job, threads);
+    let started = Instant::now();
+    sleep(duration).await;
+    search.hashes() as f64 / started.elapsed().as_secs_f64()
+}
+
+pub struct Solution {
+    pub hash: BlockHash,
+    pub nonce: u64,
+}
+
+// a nonce search running on its own OS threads, so hashing never blocks the async runtime. the nonce range is
+// split into one contiguous range per thread, and solutions come back over results. dropping the search stops it
+pub struct PowSearch {
+    pub results: mpsc::UnboundedReceiver<Solution>,
+    stop: Arc<AtomicBool>,
+    // hashes done so far across all threads, counted in batches of HASH_BATCH
+    hashes: Arc<AtomicU64>,
+}
+
+const HASH_BATCH: u64 = 4096;
+
+impl PowSearch {
+    pub fn start(job: PowJob, threads: usize) -> PowSearch {
+        let target = job.target;
+        Self::start_range(job, threads, 0..u64::MAX, target)
+    }
+
+    // searches only nonces in range. every hash meeting share_target is sent back, and the search stops at the
+    // first one that also meets the job's target. a share_target equal to the job's target sends only the solution
+    pub fn start_range(job: PowJob, threads: usize, range: Range<u64>, share_target: u64) -> PowSearch {
+        let threads = threads.max(1) as u64;
+        let (tx, results) = mpsc::unbounded_channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let hashes = Arc::new(AtomicU64::new(0));
+        let range_size = (range.end - range.start) / threads;
+
+        for worker in 0..threads {
+            let job = job.clone();
+            let tx = tx.clone();
+            let stop = Arc::clone(&stop);
+            let hashes = Arc::clone(&hashes);
+            let start = range.start + worker * range_size;
+            let end = if worker == threads - 1 { range.end } else { start + range_size };
+            thread::spawn(move || {
+                for nonce in start..end {
+                    // checking the flag on every hash would slow the search down for nothing
+                    if nonce % HASH_BATCH == 0 {
+                        if stop.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        hashes.fetch_add(HASH_BATCH, Ordering::Relaxed);
+                    }
+                    let hash = job.hash(nonce);
+                    if meets_target(&hash, share_target) {
+                        let solved = meets_target(&hash, job.target);
+                        if solved {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                        if tx.send(Solution { hash, nonce }).is_err() || solved {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        PowSearch { results, stop, hashes }
+    }
+
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for PowSearch {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
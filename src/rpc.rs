@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::block::{Block, BlockTemplate};
+use crate::hashes::Address;
+use crate::miner::Miner;
+use crate::node::NodeHandle;
+use crate::params::Network;
+use crate::payout::Payout;
+use crate::transactions::Tx;
+
+// templates reserve room for a coinbase with a single output, unless asked for more
+pub const TEMPLATE_COINBASE_BYTES: u32 = 32 + 100 + 40;
+
+// requests and responses are single lines of JSON, e.g. {"method":"getmempoolinfo"}
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum RpcRequest {
+    GetBlockchainInfo,
+    GetMempoolInfo,
+    SendRawTransaction(Tx),
+    // bytes to reserve for the coinbase
+    GetBlockTemplate(#[serde(default)] Option<u32>),
+    SubmitBlock(Block),
+    // mines blocks straight away on regtest, paying address
+    Generate { blocks: u32, address: Address },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+impl RpcResponse {
+    fn ok<T: Serialize>(result: &T) -> RpcResponse {
+        match serde_json::to_value(result) {
+            Ok(value) => RpcResponse { result: Some(value), error: None },
+            Err(e) => Self::error(e.to_string()),
+        }
+    }
+
+    fn error(message: String) -> RpcResponse {
+        RpcResponse { result: None, error: Some(message) }
+    }
+}
+
+pub async fn serve(state: NodeHandle) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // only local clients can reach the RPC server
+    let listener = TcpListener::bind(("127.0.0.1", state.params.network.rpc_port())).await?;
+    serve_on(listener, state).await
+}
+
+// serves requests from clients connecting to listener
+pub async fn serve_on(listener: TcpListener, state: NodeHandle) -> Result<(), Box<dyn Error + Send + Sync>> {
+    println!("RPC server listening on port {}", listener.local_addr()?.port());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state).await {
+                eprintln!("RPC connection error: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: NodeHandle) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<RpcRequest>(&line) {
+            Ok(request) => handle_request(request, &state).await,
+            Err(e) => RpcResponse::error(format!("invalid request: {e}")),
+        };
+        writer.write_all(serde_json::to_string(&response)?.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+async fn handle_request(request: RpcRequest, state: &NodeHandle) -> RpcResponse {
+    match request {
+        RpcRequest::GetBlockchainInfo => RpcResponse::ok(&state.chain.lock().await.get_info(&state.params)),
+        RpcRequest::GetMempoolInfo => RpcResponse::ok(&state.pool.lock().await.get_stats()),
+        RpcRequest::SendRawTransaction(tx) => {
+            let chain_lock = state.chain.lock().await;
+            match state.pool.lock().await.add_tx(tx, &chain_lock.utxos, None) {
+                Ok(accepted) => RpcResponse::ok(&accepted),
+                Err(e) => RpcResponse::error(format!("transaction rejected: {e}")),
+            }
+        }
+        RpcRequest::GetBlockTemplate(coinbase_size) => {
+            let chain_lock = state.chain.lock().await;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let pool_lock = state.pool.lock().await;
+            match BlockTemplate::new(chain_lock.chain.last().unwrap(), &pool_lock, &state.params, coinbase_size.unwrap_or(TEMPLATE_COINBASE_BYTES), now) {
+                Ok(template) => RpcResponse::ok(&template),
+                Err(e) => RpcResponse::error(format!("can't build a template: {e}")),
+            }
+        }
+        RpcRequest::SubmitBlock(block) => {
+            let hash = block.hash;
+            match state.submit_block(block).await {
+                Ok(()) => {
+                    println!("Accepted block {hash} over RPC");
+                    RpcResponse::ok(&hash)
+                }
+                Err(e) => RpcResponse::error(format!("block rejected: {e}")),
+            }
+        }
+        RpcRequest::Generate { blocks, address } => {
+            let generated = match Payout::single(address) {
+                Ok(payout) => Miner::generate_blocks(state, blocks, &payout).await,
+                Err(e) => Err(e.into()),
+            };
+            match generated {
+                Ok(hashes) => RpcResponse::ok(&hashes),
+                Err(e) => RpcResponse::error(format!("generate failed: {e}")),
+            }
+        }
+    }
+}
+
+// sends a single request to the local node of network and waits for its result, used by the CLI
+pub async fn call(network: Network, request: &RpcRequest) -> Result<serde_json::Value, Box<dyn Error>> {
+    call_at(network.rpc_port(), request).await
+}
+
+// same as call, for a local node serving RPC on port
+pub async fn call_at(port: u16, request: &RpcRequest) -> Result<serde_json::Value, Box<dyn Error>> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(serde_json::to_string(request)?.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+
+    let line = BufReader::new(reader).lines().next_line().await?.ok_or("node closed the connection")?;
+    let response: RpcResponse = serde_json::from_str(&line)?;
+    match response.error {
+        Some(error) => Err(error.into()),
+        None => Ok(response.result.unwrap_or(serde_json::Value::Null)),
+    }
+}
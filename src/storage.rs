@@ -0,0 +1,13 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+// writes contents to a temporary file next to path, then renames it over path. a crash part way through leaves
+// either the old file or the new one, never a truncated one
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
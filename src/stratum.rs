@@ -0,0 +1,401 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Mutex};
+use tokio::time::sleep;
+
+use crate::amount::Amount;
+use crate::block::{Block, BlockTemplate};
+use crate::hashes::BlockHash;
+use crate::miner::Miner;
+use crate::params::Network;
+use crate::payout::Payout;
+use crate::pow::{self, PowJob, PowSearch};
+use crate::rpc::{self, RpcRequest};
+
+// shares are this many times easier to find than blocks
+pub const DEFAULT_SHARE_DIFFICULTY: u64 = 256;
+// how often the node is asked for a new template
+const TEMPLATE_POLL_SECS: u64 = 5;
+// each worker connection gets its own 2^40 nonces of every job, so workers never hash the same header
+const WORKER_NONCE_BITS: u32 = 40;
+
+// worker to pool, one JSON object per line, e.g. {"method":"login","params":{"worker":"rig1"}}
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum StratumRequest {
+    Login { worker: String },
+    Submit { job_id: u64, nonce: u64 },
+}
+
+// pool to worker
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum StratumMessage {
+    Job(StratumJob),
+    Accepted { job_id: u64, nonce: u64, block: bool },
+    Rejected { job_id: u64, nonce: u64, reason: String },
+}
+
+// header to hash, the share target and the nonces this worker should try
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StratumJob {
+    pub job_id: u64,
+    pub header: PowJob,
+    pub share_target: u64,
+    pub nonce_start: u64,
+    pub nonce_end: u64,
+}
+
+#[derive(Default, Debug)]
+pub struct WorkerStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub blocks: u64,
+}
+
+// a block built from a template, missing only its hash and nonce
+struct PoolJob {
+    id: u64,
+    block: Block,
+    fees: Amount,
+    // nonces already submitted for this job
+    shares: HashSet<u64>,
+}
+
+struct PoolState {
+    job: Option<PoolJob>,
+    workers: HashMap<String, WorkerStats>,
+    next_worker: u64,
+}
+
+// hands out jobs built from the local node's block templates to workers over TCP, counts their shares and
+// submits solved blocks back to the node
+#[derive(Clone)]
+pub struct StratumServer {
+    // the node's network, which decides the ports of its RPC server and of the pool
+    network: Network,
+    // where templates are fetched and blocks submitted, the network's RPC port
+    rpc_port: u16,
+    payout: Payout,
+    // bytes the payout's coinbase takes up, templates leave room for it
+    coinbase_size: u32,
+    share_difficulty: u64,
+    state: Arc<Mutex<PoolState>>,
+    // id of the current job, workers get the new one as soon as it changes
+    job_tx: Arc<watch::Sender<u64>>,
+}
+
+impl StratumServer {
+    pub fn new(network: Network, payout: Payout, share_difficulty: u64) -> StratumServer {
+        let coinbase_size = Miner::generate_coinbase(Amount::ZERO, &payout, 0).get_size();
+        let state = PoolState { job: None, workers: HashMap::new(), next_worker: 0 };
+        let (job_tx, _) = watch::channel(0);
+        StratumServer { network, rpc_port: network.rpc_port(), payout, coinbase_size, share_difficulty: share_difficulty.max(1), state: Arc::new(Mutex::new(state)), job_tx: Arc::new(job_tx) }
+    }
+
+    pub async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let server = self.clone();
+        tokio::spawn(async move { server.refresh_jobs().await });
+
+        let port = self.network.stratum_port();
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        println!("Mining pool listening on port {port}");
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_connection(stream).await {
+                    eprintln!("Worker {peer} disconnected: {e}");
+                }
+            });
+        }
+    }
+
+    // polls the node for templates, and starts a new job when the tip changes or the template pays more
+    async fn refresh_jobs(&self) {
+        loop {
+            if let Err(e) = self.refresh_job().await {
+                eprintln!("Error getting block template: {e}");
+            }
+            sleep(Duration::from_secs(TEMPLATE_POLL_SECS)).await;
+        }
+    }
+
+    async fn refresh_job(&self) -> Result<(), Box<dyn Error>> {
+        let result = rpc::call_at(self.rpc_port, &RpcRequest::GetBlockTemplate(Some(self.coinbase_size))).await?;
+        self.update_job(serde_json::from_value(result)?).await;
+        Ok(())
+    }
+
+    async fn update_job(&self, template: BlockTemplate) {
+        let mut state = self.state.lock().await;
+        if let Some(job) = state.job.as_ref() {
+            if job.block.previous_hash == template.previous_hash && job.fees >= template.fees {
+                return;
+            }
+        }
+        let id = state.job.as_ref().map_or(1, |job| job.id + 1);
+        let mut transactions = vec![Miner::generate_coinbase(template.coinbase_value, &self.payout, 0)];
+        transactions.extend(template.transactions);
+        let block = Block { index: template.index, hash: BlockHash::ZERO, previous_hash: template.previous_hash, time: template.time,
+            target: template.target, nonce: 0, merkle_root: Block::calc_merkle_root(&transactions),
+            witness_root: Block::calc_witness_root(&transactions), transactions };
+        println!("New job {id} for block {} with {} transaction(s)", block.index, block.transactions.len());
+        state.job = Some(PoolJob { id, block, fees: template.fees, shares: HashSet::new() });
+        self.job_tx.send_replace(id);
+    }
+
+    fn share_target(&self, target: u64) -> u64 {
+        target.saturating_mul(self.share_difficulty)
+    }
+
+    async fn worker_job(&self, worker_index: u64) -> Option<StratumJob> {
+        let state = self.state.lock().await;
+        let job = state.job.as_ref()?;
+        let nonce_start = worker_index << WORKER_NONCE_BITS;
+        Some(StratumJob { job_id: job.id, header: job.block.pow_job(), share_target: self.share_target(job.block.target),
+            nonce_start, nonce_end: nonce_start.saturating_add(1 << WORKER_NONCE_BITS) })
+    }
+
+    async fn handle_connection(&self, stream: TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut job_rx = self.job_tx.subscribe();
+        // name and nonce partition, set on login
+        let mut worker: Option<(String, u64)> = None;
+
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else { return Ok(()) };
+                    match serde_json::from_str::<StratumRequest>(&line) {
+                        Ok(StratumRequest::Login { worker: name }) => {
+                            let index = {
+                                let mut state = self.state.lock().await;
+                                state.workers.entry(name.clone()).or_default();
+                                state.next_worker += 1;
+                                // the nonce space is shared out again once 2^24 connections have come and gone
+                                state.next_worker % (1 << (64 - WORKER_NONCE_BITS))
+                            };
+                            println!("Worker {name} logged in");
+                            worker = Some((name, index));
+                            job_rx.borrow_and_update();
+                            if let Some(job) = self.worker_job(index).await {
+                                send(&mut writer, &StratumMessage::Job(job)).await?;
+                            }
+                        }
+                        Ok(StratumRequest::Submit { job_id, nonce }) => {
+                            let Some((name, index)) = worker.as_ref() else { return Err("share submitted before login".into()) };
+                            let message = self.check_share(name, *index, job_id, nonce).await;
+                            send(&mut writer, &message).await?;
+                        }
+                        Err(e) => return Err(format!("invalid request: {e}").into()),
+                    }
+                }
+                changed = job_rx.changed() => {
+                    changed?;
+                    if let Some((_, index)) = worker.as_ref() {
+                        if let Some(job) = self.worker_job(*index).await {
+                            send(&mut writer, &StratumMessage::Job(job)).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn check_share(&self, name: &str, worker_index: u64, job_id: u64, nonce: u64) -> StratumMessage {
+        let mut state = self.state.lock().await;
+        let share_target = state.job.as_ref().map_or(0, |job| self.share_target(job.block.target));
+        let verdict = match state.job.as_mut() {
+            Some(job) if job.id == job_id => {
+                let hash = job.block.pow_job().hash(nonce);
+                if nonce >> WORKER_NONCE_BITS != worker_index {
+                    Err("nonce outside the assigned range")
+                } else if !job.shares.insert(nonce) {
+                    Err("duplicate share")
+                } else if !pow::meets_target(&hash, share_target) {
+                    Err("hash does not meet the share target")
+                } else if pow::meets_target(&hash, job.block.target) {
+                    let mut block = job.block.clone();
+                    block.hash = hash;
+                    block.nonce = nonce;
+                    Ok(Some(block))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Err("stale job"),
+        };
+
+        let block = match verdict {
+            Err(reason) => {
+                state.workers.entry(name.to_string()).or_default().rejected += 1;
+                return StratumMessage::Rejected { job_id, nonce, reason: reason.to_string() };
+            }
+            Ok(None) => {
+                state.workers.entry(name.to_string()).or_default().accepted += 1;
+                return StratumMessage::Accepted { job_id, nonce, block: false };
+            }
+            Ok(Some(block)) => block,
+        };
+        // the node is slow to answer compared to share checks, other workers shouldn't have to wait for it
+        drop(state);
+
+        let submitted = rpc::call_at(self.rpc_port, &RpcRequest::SubmitBlock(block.clone())).await.map_err(|e| e.to_string());
+        let mut state = self.state.lock().await;
+        let stats = state.workers.entry(name.to_string()).or_default();
+        match submitted {
+            Ok(_) => {
+                stats.accepted += 1;
+                stats.blocks += 1;
+                println!("Worker {name} found block {} {}", block.index, block.hash);
+                self.print_workers(&state.workers);
+                StratumMessage::Accepted { job_id, nonce, block: true }
+            }
+            Err(e) => {
+                stats.rejected += 1;
+                eprintln!("Node rejected block {} from worker {name}: {e}", block.hash);
+                StratumMessage::Rejected { job_id, nonce, reason: e }
+            }
+        }
+    }
+
+    fn print_workers(&self, workers: &HashMap<String, WorkerStats>) {
+        println!("{:<20}{:<12}{:<12}{}", "Worker", "Accepted", "Rejected", "Blocks");
+        let mut names: Vec<&String> = workers.keys().collect();
+        names.sort();
+        for name in names {
+            let stats = &workers[name];
+            println!("{:<20}{:<12}{:<12}{}", name, stats.accepted, stats.rejected, stats.blocks);
+        }
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, message: &StratumMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+    writer.write_all(serde_json::to_string(message)?.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    Ok(())
+}
+
+// connects to a pool as worker and hashes every job it's given, submitting each share found
+pub async fn run_worker(pool: &str, worker: String, threads: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stream = TcpStream::connect(pool).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(serde_json::to_string(&StratumRequest::Login { worker })?.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+
+    let mut search: Option<(u64, PowSearch)> = None;
+    loop {
+        // with no job yet, only messages from the pool can wake the worker
+        let share = async {
+            match search.as_mut() {
+                Some((job_id, search)) => (*job_id, search.results.recv().await),
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            (job_id, solution) = share => {
+                let Some(solution) = solution else {
+                    // the whole range was searched, nothing to do until the next job
+                    println!("Nonce range of job {job_id} exhausted, waiting for a new job");
+                    search = None;
+                    continue;
+                };
+                let request = StratumRequest::Submit { job_id, nonce: solution.nonce };
+                writer.write_all(serde_json::to_string(&request)?.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+            line = lines.next_line() => {
+                let Some(line) = line? else { return Err("pool closed the connection".into()) };
+                match serde_json::from_str::<StratumMessage>(&line)? {
+                    StratumMessage::Job(job) => {
+                        println!("Working on job {} for block {}", job.job_id, job.header.index);
+                        // replacing the old search drops it, which stops its threads
+                        search = Some((job.job_id, PowSearch::start_range(job.header, threads, job.nonce_start..job.nonce_end, job.share_target)));
+                    }
+                    StratumMessage::Accepted { nonce, block, .. } => {
+                        println!("Share {nonce:016x} accepted{}", if block { ", block found!" } else { "" });
+                    }
+                    StratumMessage::Rejected { nonce, reason, .. } => println!("Share {nonce:016x} rejected: {reason}"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mempool::Mempool;
+    use crate::params::ChainParams;
+    use crate::testing;
+
+    fn is_accepted(message: &StratumMessage, block_found: bool) -> bool {
+        matches!(message, StratumMessage::Accepted { block, .. } if *block == block_found)
+    }
+
+    fn rejection(message: &StratumMessage) -> Option<&str> {
+        match message {
+            StratumMessage::Rejected { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+
+    // with a share difficulty of 1 on regtest every valid share is also a block
+    #[tokio::test]
+    async fn shares_are_counted_and_blocks_reach_the_node() {
+        let key = testing::key(1);
+        let node = testing::regtest_node("stratum", &key, 0).await.handle();
+        // a port of its own, so a regtest node already running locally is never the one reached
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let mut server = StratumServer::new(Network::Regtest, Payout::single(testing::address(&key)).unwrap(), 1);
+        server.rpc_port = listener.local_addr().unwrap().port();
+        tokio::spawn(rpc::serve_on(listener, node.clone()));
+        server.refresh_job().await.unwrap();
+        let job_id = server.state.lock().await.job.as_ref().unwrap().id;
+        let nonce = 1 << WORKER_NONCE_BITS;
+
+        assert_eq!(rejection(&server.check_share("rig", 1, job_id, 0).await), Some("nonce outside the assigned range"));
+        assert!(is_accepted(&server.check_share("rig", 1, job_id, nonce).await, true));
+        assert_eq!(node.chain.lock().await.get_height(), 1);
+        assert_eq!(rejection(&server.check_share("rig", 1, job_id, nonce).await), Some("duplicate share"));
+        // a timestamp this far ahead passes the share check, but not the node's
+        server.state.lock().await.job.as_mut().unwrap().block.time = u64::MAX / 2;
+        let reason = rejection(&server.check_share("rig", 1, job_id, nonce + 1).await).unwrap().to_string();
+        assert!(reason.starts_with("block rejected"), "{reason}");
+        assert_eq!(node.chain.lock().await.get_height(), 1);
+        assert_eq!(rejection(&server.check_share("rig", 1, job_id + 1, nonce + 2).await), Some("stale job"));
+
+        {
+            let state = server.state.lock().await;
+            let stats = &state.workers["rig"];
+            assert_eq!((stats.accepted, stats.rejected, stats.blocks), (1, 4, 1));
+        }
+
+        // the next job builds on the block just found
+        server.refresh_job().await.unwrap();
+        let state = server.state.lock().await;
+        let job = state.job.as_ref().unwrap();
+        assert_eq!(job.id, job_id + 1);
+        assert_eq!(job.block.previous_hash, node.chain.lock().await.get_current_hash());
+    }
+
+    #[tokio::test]
+    async fn last_worker_range_ends_at_the_top_of_the_nonce_space() {
+        let server = StratumServer::new(Network::Regtest, Payout::single(testing::address(&testing::key(1))).unwrap(), 1);
+        let template = BlockTemplate::new(&ChainParams::regtest().genesis_block(), &Mempool::new(), &ChainParams::regtest(), server.coinbase_size, 0).unwrap();
+        server.update_job(template).await;
+        let last = (1 << (64 - WORKER_NONCE_BITS)) - 1;
+        let job = server.worker_job(last).await.unwrap();
+        assert_eq!((job.nonce_start, job.nonce_end), (last << WORKER_NONCE_BITS, u64::MAX));
+    }
+}
@@ -0,0 +1,69 @@
+// helpers for tests that need keys, a regtest node and coins to spend
+use std::fs;
+use std::path::PathBuf;
+
+use ed25519_dalek::{Signer, SigningKey};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::hashes::{Address, BlockHash};
+use crate::input::Input;
+use crate::miner::Miner;
+use crate::node::{Node, NodeHandle};
+use crate::output::Output;
+use crate::params::ChainParams;
+use crate::payout::Payout;
+use crate::pow;
+use crate::transactions::Tx;
+
+pub fn key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+}
+
+pub fn address(key: &SigningKey) -> Address {
+    Address::from_bytes(key.verifying_key().to_bytes())
+}
+
+// an empty data directory for each test, so nothing saved by an earlier run is loaded
+pub fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chain-test-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+// a regtest node with blocks already generated, each paying its whole coinbase to key
+pub async fn regtest_node(name: &str, key: &SigningKey, blocks: u32) -> Node {
+    let node = Node::new(&ChainParams::regtest(), data_dir(name));
+    Miner::generate_blocks(&node.handle(), blocks, &Payout::single(address(key)).unwrap()).await.unwrap();
+    node
+}
+
+pub async fn add_to_pool(node: &NodeHandle, tx: Tx) {
+    let chain_lock = node.chain.lock().await;
+    node.pool.lock().await.add_tx(tx, &chain_lock.utxos, None).unwrap();
+}
+
+// spends output vout of parent, which pays key, sending all of it except fee to to
+pub fn spend(key: &SigningKey, parent: &Tx, vout: u32, to: Address, fee: Amount) -> Tx {
+    let amount = parent.outputs[vout as usize].amount.checked_sub(fee).unwrap();
+    pay(key, parent, vout, vec![Output { amount, address: to }])
+}
+
+// spends output vout of parent, which pays key, to outputs
+pub fn pay(key: &SigningKey, parent: &Tx, vout: u32, outputs: Vec<Output>) -> Tx {
+    let mut inputs = vec![Input { txid: parent.txid, vout, signature: [0; 64] }];
+    inputs[0].signature = key.sign(&Tx::sighash(&inputs[0].outpoint(), &outputs)).to_bytes();
+    Tx { txid: Tx::generate_txid(&inputs, &outputs), inputs, outputs }
+}
+
+// a regtest block on parent whose coinbase pays coinbase to to, built by hand so it can go on any branch
+pub fn block_on(parent: &Block, coinbase: Amount, to: Address, transactions: Vec<Tx>) -> Block {
+    let mut transactions = transactions;
+    transactions.insert(0, Miner::generate_coinbase(coinbase, &Payout::single(to).unwrap(), 0));
+    let mut block = Block { index: parent.index + 1, hash: BlockHash::ZERO, previous_hash: parent.hash, time: parent.time, target: parent.target,
+        nonce: 0, merkle_root: Block::calc_merkle_root(&transactions), witness_root: Block::calc_witness_root(&transactions), transactions };
+    let job = block.pow_job();
+    block.nonce = (0..).find(|nonce| pow::meets_target(&job.hash(*nonce), job.target)).unwrap();
+    block.hash = job.hash(block.nonce);
+    block
+}
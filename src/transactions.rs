@@ -1,95 +1,135 @@
 use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
 
 use blake3;
 use ed25519_dalek::{Signature, Verifier, VerifyingKey};
-use num_format::{Locale, ToFormattedString};
 use serde::{Deserialize, Serialize};
-use crate::blockchain::Blockchain;
-use crate::input::Input;
+use crate::amount::Amount;
+use crate::hashes::{to_hex, Txid, Wtxid};
+use crate::input::{Input, OutPoint};
 use crate::output::Output;
 
 #[derive(Clone, Hash, Serialize, Deserialize)]
 pub struct Tx {
-    pub txid: [u8;32],
+    pub txid: Txid,
     pub inputs: Vec<Input>,
     pub outputs: Vec<Output>,
 }
 
 impl Tx {
-    pub fn generate_txid(inputs: &Vec<Input>, outputs: &Vec<Output>) -> [u8;32]{
+    // the txid leaves out input signatures, so re-signing or re-encoding a transaction never changes
+    // the id that its children reference
+    pub fn generate_txid(inputs: &Vec<Input>, outputs: &Vec<Output>) -> Txid{
         let mut hasher = blake3::Hasher::new();
         inputs.iter().for_each(|input|{
-            hasher.update(&input.txid);
+            hasher.update(input.txid.as_bytes());
+            hasher.update(&input.vout.to_be_bytes());
+            // coinbase inputs carry arbitrary data instead of a signature, which keeps their txids unique
+            if input.is_coinbase() {
+                hasher.update(&input.signature);
+            }
+        });
+        outputs.iter().for_each(|output|{
+            hasher.update(&output.amount.to_base_units().to_be_bytes());
+            hasher.update(output.address.as_bytes());
+        });
+
+        Txid::from_bytes(*hasher.finalize().as_bytes())
+    }
+
+    // the witness id commits to the whole transaction, signatures included
+    pub fn generate_wtxid(txid: &Txid, inputs: &Vec<Input>) -> Wtxid{
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(txid.as_bytes());
+        inputs.iter().for_each(|input|{
             hasher.update(&input.signature);
-            //hasher.update(&input.timestamp.to_be_bytes());
         });
+
+        Wtxid::from_bytes(*hasher.finalize().as_bytes())
+    }
+
+    // what the owner of the output at outpoint signs to spend it: the outpoint and every output of the spending
+    // transaction, so a relayed transaction can't be redirected or have its amounts changed
+    pub fn sighash(outpoint: &OutPoint, outputs: &[Output]) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(outpoint.txid.as_bytes());
+        hasher.update(&outpoint.vout.to_be_bytes());
         outputs.iter().for_each(|output|{
-            hasher.update(&output.amount.to_be_bytes());
-            hasher.update(&output.address);
+            hasher.update(&output.amount.to_base_units().to_be_bytes());
+            hasher.update(output.address.as_bytes());
         });
 
         *hasher.finalize().as_bytes()
     }
 
+    pub fn wtxid(&self) -> Wtxid {
+        Self::generate_wtxid(&self.txid, &self.inputs)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].is_coinbase()
+    }
+
     pub fn print(&self) {
-        print!("------------------------------------------------------------\nTransaction ");
-        self.txid.iter().for_each(|hex| print!("{:02x}",hex));
+        println!("------------------------------------------------------------\nTransaction {}", self.txid);
+        print!("Witness id: {}", self.wtxid());
         for (index, input) in self.inputs.iter().enumerate(){
             println!("\n\nInput {index}");
-            print!("Txid: ");
-            input.txid.iter().for_each(|hex|print!("{:02x}",hex));
-            print!("\nSignature: ");
-            input.signature.iter().for_each(|hex|print!("{:02x}",hex));
+            println!("Txid: {}", input.txid);
+            println!("Output index: {}", input.vout);
+            print!("Signature: {}", to_hex(&input.signature));
         }
         for (index, output) in self.outputs.iter().enumerate() {
             println!("\n\nOutput {index}");
-            println!("Amount: {}",output.amount.to_formatted_string(&Locale::en));
-            print!("Address: ");
-            output.address.iter().for_each(|hex|print!("{:02x}",hex));
+            println!("Amount: {}",output.amount);
+            print!("Address: {}", output.address);
         }
-        println!("\n\nEnd Transaction ");
-        self.txid.iter().for_each(|hex| print!("{:02x}",hex));
-        println!("\n------------------------------------------------------------");
+        println!("\n\nEnd Transaction {}", self.txid);
+        println!("------------------------------------------------------------");
+    }
+
+    // the first outpoint spent by more than one of the inputs
+    pub fn find_duplicate_input(&self) -> Option<OutPoint> {
+        let mut outpoints = HashSet::new();
+        self.inputs.iter().map(|input| input.outpoint()).find(|outpoint| !outpoints.insert(*outpoint))
     }
 
     pub fn get_size(&self) -> u32{
         const TXID_BYTES: u32 = 32;
-        // inputs are always 96 bytes ( 32 bytes for txid, and 64 bytes for signature)
-        let input_bytes: u32 = self.inputs.iter().map(|_|96).sum();
+        // inputs are always 100 bytes ( 32 bytes for txid, 4 bytes for output index, and 64 bytes for signature)
+        let input_bytes: u32 = self.inputs.iter().map(|_|100).sum();
         // outputs are always 40 bytes (8 bytes for amount, 32 bytes for address)
         let output_bytes: u32 = self.outputs.iter().map(|_|40).sum();
         TXID_BYTES + input_bytes + output_bytes
     }
 
-    fn calc_sum_of_inputs(&self, chain: &Blockchain) -> u64{
-        self.inputs.iter().flat_map(|input| {
-            // for each input, we scan the chain for a corresponding output
-            chain.chain.iter().flat_map(|block| {
-                block.transactions.iter().flat_map(|btx| {
-                    btx.outputs.iter().map(|out| {
-                        // if the output matches the input, and the output is being sent to the correct address, it is the value used in input
-                        if btx.txid == input.txid && VerifyingKey::from_bytes(&out.address).unwrap().verify(&input.txid, &Signature::from_bytes(&input.signature)).is_ok() {
-                            out.amount
-                        } else { 0 }
-                    })
-                })
-            })
-        }).sum()
+    fn calc_sum_of_outputs(&self) -> Result<Amount, TxError>{
+        Amount::checked_sum(self.outputs.iter().map(|out|out.amount)).ok_or(TxError::AmountOverflow)
     }
 
-    fn calc_sum_of_outputs(&self) -> u64{
-        self.outputs.iter().map(|out|out.amount).sum()
+    // fee rates are fixed point base units per byte, with 16 fractional bits
+    pub fn fee_rate(fee: Amount, size: u32) -> u64 {
+        (fee.to_base_units() << 16) / size as u64
     }
 
-    pub fn calc_mining_fee_per_byte(&self, chain: &Blockchain) -> u64 {
-        let fee = self.calc_sum_of_inputs(chain) - self.calc_sum_of_outputs();
-        let size = self.get_size();
-
-        fee << 16 / size as u64
+    // the smallest fee whose fee_rate over size bytes is at least fee_rate
+    pub fn fee_for_rate(fee_rate: u64, size: u32) -> Amount {
+        Amount::from_base_units((fee_rate as u128 * size as u128).div_ceil(1 << 16) as u64)
     }
 
-    pub fn calc_mining_fee(&self, chain: &Blockchain) -> u64 {
-        self.calc_sum_of_inputs(chain) - self.calc_sum_of_outputs()
+    // the fee tx pays, checking each input's signature against the output it spends. spent_output looks the
+    // outputs up in the utxo set, the mempool or earlier transactions of the same block
+    pub fn calc_mining_fee_from<F: Fn(&OutPoint) -> Option<Output>>(&self, spent_output: F) -> Result<Amount, TxError> {
+        let mut sum_of_inputs = Amount::ZERO;
+        for input in self.inputs.iter() {
+            let out = spent_output(&input.outpoint()).ok_or(TxError::MissingInput(input.outpoint()))?;
+            let key = VerifyingKey::from_bytes(out.address.as_bytes()).map_err(|_| TxError::InvalidSignature)?;
+            key.verify(&Self::sighash(&input.outpoint(), &self.outputs), &Signature::from_bytes(&input.signature)).map_err(|_| TxError::InvalidSignature)?;
+            sum_of_inputs = sum_of_inputs.checked_add(out.amount).ok_or(TxError::AmountOverflow)?;
+        }
+        sum_of_inputs.checked_sub(self.calc_sum_of_outputs()?).ok_or(TxError::InsufficientBalance)
     }
 }
 
@@ -116,4 +156,56 @@ impl Ord for Tx {
 #[derive(Debug)]
 pub enum TxError{
     InsufficientBalance,
-}
\ No newline at end of file
+    AmountOverflow,
+    MissingInput(OutPoint),
+    InvalidSignature,
+    DuplicateInput(OutPoint),
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::InsufficientBalance => write!(f, "outputs pay more than the inputs hold"),
+            TxError::AmountOverflow => write!(f, "amount overflow"),
+            TxError::MissingInput(outpoint) => write!(f, "input {}:{} is missing or already spent", outpoint.txid, outpoint.vout),
+            TxError::InvalidSignature => write!(f, "invalid signature"),
+            TxError::DuplicateInput(outpoint) => write!(f, "input {}:{} is spent twice", outpoint.txid, outpoint.vout),
+        }
+    }
+}
+
+impl Error for TxError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn signatures_cover_the_outpoint_and_the_outputs() {
+        let key = testing::key(1);
+        let parent = Tx { txid: Txid::from_bytes([9; 32]), inputs: vec![], outputs: vec![Output { amount: Amount::from_base_units(1000), address: testing::address(&key) }] };
+        let spent_output = |outpoint: &OutPoint| (outpoint.txid == parent.txid).then(|| parent.outputs[outpoint.vout as usize].clone());
+        let tx = testing::spend(&key, &parent, 0, testing::address(&testing::key(2)), Amount::from_base_units(100));
+        assert_eq!(tx.calc_mining_fee_from(spent_output).unwrap(), Amount::from_base_units(100));
+
+        // redirecting the payment, or taking more of it as fee, breaks the signature
+        let mut redirected = tx.clone();
+        redirected.outputs[0].address = testing::address(&testing::key(3));
+        assert!(matches!(redirected.calc_mining_fee_from(spent_output), Err(TxError::InvalidSignature)));
+        let mut reduced = tx.clone();
+        reduced.outputs[0].amount = Amount::from_base_units(500);
+        assert!(matches!(reduced.calc_mining_fee_from(spent_output), Err(TxError::InvalidSignature)));
+    }
+
+    #[test]
+    fn fee_for_rate_is_the_smallest_fee_reaching_the_rate() {
+        for (fee_rate, size) in [(381024, 172), (1 << 16, 172), (65535, 1000), (1, 172), (0, 172)] {
+            let fee = Tx::fee_for_rate(fee_rate, size);
+            assert!(Tx::fee_rate(fee, size) >= fee_rate);
+            if let Some(less) = fee.checked_sub(Amount::from_base_units(1)) {
+                assert!(Tx::fee_rate(less, size) < fee_rate);
+            }
+        }
+    }
+}